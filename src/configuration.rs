@@ -0,0 +1,216 @@
+use std::sync::Arc;
+
+use lsp_types::{notification, request, ConfigurationParams, DidChangeConfigurationParams, Uri};
+use parking_lot::Mutex;
+use serde_json::{Map, Value};
+
+use crate::{
+    listener::{Listener, Notifier},
+    utils, Error,
+};
+
+// The settings, and their overrides for parts of the workspace
+//
+// * `settings`: Settings of the whole workspace
+// * `scoped`: Overrides of a folder or a document, the innermost scope is applied last
+#[derive(Default)]
+struct State {
+    settings: Value,
+    scoped: Vec<(Uri, Value)>,
+}
+
+impl State {
+    // Settings of the scope, with the overrides of every scope containing it merged in
+    fn resolve(&self, scope: Option<&Uri>) -> Value {
+        let mut settings = self.settings.clone();
+
+        if let Some(scope) = scope {
+            let mut overrides = self
+                .scoped
+                .iter()
+                .filter(|(uri, _)| utils::uri_contains(uri, scope))
+                .collect::<Vec<_>>();
+            overrides.sort_by_key(|(uri, _)| uri.as_str().len());
+
+            for (_, value) in overrides {
+                merge(&mut settings, value.clone());
+            }
+        }
+
+        settings
+    }
+}
+
+/// The settings of the client, answering `workspace/configuration` and pushed with
+/// `workspace/didChangeConfiguration` when they change
+/// Settings are a JSON tree, sections are dotted paths in it, e.g. `python.analysis` is
+/// `{ "python": { "analysis": { ... } } }`. Settings can be overridden for a folder or a
+/// document, the overrides of a scope apply to every uri inside it
+///
+/// `workspace/configuration` is answered from the start of the server, register a handler with
+/// [crate::process::LanguageServer::on_request] or a default reply with
+/// [crate::process::LanguageServer::set_default_reply] to override it
+///
+/// # Usage
+/// ```rust
+///     let configuration = server.configuration();
+///     configuration.set("python.analysis.typeCheckingMode", json!("strict"))?;
+///     configuration.set_scoped(folder_uri, "python.analysis.typeCheckingMode", json!("basic"))?;
+/// ```
+#[derive(Clone)]
+pub struct ConfigurationProvider {
+    state: Arc<Mutex<State>>,
+    notifier: Notifier,
+}
+
+impl ConfigurationProvider {
+    pub(crate) fn new(listener: &Listener) -> Self {
+        let provider = Self {
+            state: Default::default(),
+            notifier: listener.notifier(),
+        };
+
+        listener
+            .on_fallback_request::<request::WorkspaceConfiguration, _, _, _>({
+                let provider = provider.clone();
+                move |params: ConfigurationParams| {
+                    let state = provider.state.lock();
+                    let items = params
+                        .items
+                        .into_iter()
+                        .map(|item| {
+                            let settings = state.resolve(item.scope_uri.as_ref());
+                            match item.section {
+                                Some(section) => section_of(&settings, &section),
+                                None => settings,
+                            }
+                        })
+                        .collect::<Vec<_>>();
+
+                    async { Ok(items) }
+                }
+            })
+            .detach();
+
+        provider
+    }
+
+    /// The settings of the whole workspace, without the overrides
+    pub fn settings(&self) -> Value {
+        self.state.lock().settings.clone()
+    }
+
+    /// The settings as the server gets them
+    ///
+    /// * `section`: Dotted path of the section, `None` for every setting
+    /// * `scope`: Folder or document the settings apply to, `None` ignores the overrides
+    pub fn get(&self, section: Option<&str>, scope: Option<&Uri>) -> Value {
+        let settings = self.state.lock().resolve(scope);
+        match section {
+            Some(section) => section_of(&settings, section),
+            None => settings,
+        }
+    }
+
+    /// Replace every setting of the workspace, then send `workspace/didChangeConfiguration`
+    ///
+    /// * `settings`: New settings tree
+    pub fn replace(&self, settings: Value) -> Result<(), Error> {
+        self.state.lock().settings = settings;
+        self.changed()
+    }
+
+    /// Set a section of the settings, then send `workspace/didChangeConfiguration`
+    /// The missing parents of the section are created
+    ///
+    /// * `section`: Dotted path of the section
+    /// * `value`: New value of the section
+    pub fn set(&self, section: &str, value: Value) -> Result<(), Error> {
+        set_section(&mut self.state.lock().settings, section, value);
+        self.changed()
+    }
+
+    /// Override a section of the settings for a folder or a document, then send
+    /// `workspace/didChangeConfiguration`
+    ///
+    /// * `scope`: Folder or document the override applies to
+    /// * `section`: Dotted path of the section
+    /// * `value`: New value of the section
+    pub fn set_scoped(&self, scope: Uri, section: &str, value: Value) -> Result<(), Error> {
+        {
+            let mut state = self.state.lock();
+            match state.scoped.iter_mut().find(|(uri, _)| *uri == scope) {
+                Some((_, settings)) => set_section(settings, section, value),
+                None => {
+                    let mut settings = Value::Null;
+                    set_section(&mut settings, section, value);
+                    state.scoped.push((scope, settings));
+                }
+            }
+        }
+
+        self.changed()
+    }
+
+    /// Remove the overrides of a folder or a document, then send
+    /// `workspace/didChangeConfiguration`
+    pub fn remove_scope(&self, scope: &Uri) -> Result<(), Error> {
+        self.state.lock().scoped.retain(|(uri, _)| uri != scope);
+        self.changed()
+    }
+
+    // Tell the server the settings changed, once the handshake is done. The settings are sent
+    // along for the servers not pulling them
+    fn changed(&self) -> Result<(), Error> {
+        let settings = self.settings();
+
+        match self
+            .notifier
+            .notify_checked::<notification::DidChangeConfiguration>(DidChangeConfigurationParams {
+                settings,
+            }) {
+            // The server will pull the settings once initialized
+            Err(Error::ServerNotInitialized) => Ok(()),
+            result => result,
+        }
+    }
+}
+
+// Value of a dotted section, `null` if it is missing
+fn section_of(settings: &Value, section: &str) -> Value {
+    section
+        .split('.')
+        .filter(|key| !key.is_empty())
+        .try_fold(settings, |value, key| value.get(key))
+        .cloned()
+        .unwrap_or(Value::Null)
+}
+
+// Set a dotted section, replacing the values on its path which aren't objects
+fn set_section(settings: &mut Value, section: &str, value: Value) {
+    let mut current = settings;
+    for key in section.split('.').filter(|key| !key.is_empty()) {
+        if !current.is_object() {
+            *current = Value::Object(Map::new());
+        }
+
+        let Value::Object(object) = current else {
+            return;
+        };
+        current = object.entry(key).or_insert(Value::Null);
+    }
+
+    *current = value;
+}
+
+// Merge the overrides into the settings, objects are merged key by key
+fn merge(settings: &mut Value, overrides: Value) {
+    match (settings, overrides) {
+        (Value::Object(settings), Value::Object(overrides)) => {
+            for (key, value) in overrides {
+                merge(settings.entry(key).or_insert(Value::Null), value);
+            }
+        }
+        (settings, overrides) => *settings = overrides,
+    }
+}
@@ -0,0 +1,563 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Weak},
+    time::Instant,
+};
+
+use futures::StreamExt;
+use lsp_types::{
+    notification, request, request::Request, Diagnostic, DiagnosticOptions,
+    DiagnosticServerCancellationData, DiagnosticServerCapabilities, DiagnosticSeverity,
+    DocumentDiagnosticParams, DocumentDiagnosticReport, DocumentDiagnosticReportKind,
+    DocumentDiagnosticReportResult, PreviousResultId, ServerCapabilities, TextDocumentIdentifier,
+    Uri, WorkspaceDiagnosticParams, WorkspaceDiagnosticReportResult,
+    WorkspaceDocumentDiagnosticReport,
+};
+use parking_lot::{Mutex, RwLock};
+use tokio::{select, sync::Notify};
+
+use crate::{
+    document::{DocumentEvent, DocumentStore},
+    listener::Listener,
+    stream::{self, LagPolicy, NotificationStream, PartialResult, StreamSender},
+    Error, NOTIFICATION_STREAM_CAPACITY, WORKSPACE_DIAGNOSTIC_INTERVAL,
+};
+
+/// Latest diagnostics of a document
+///
+/// * `version`: Version of the document the diagnostics apply to, if the server told it
+/// * `diagnostics`: The diagnostics
+#[derive(Debug, Clone, PartialEq)]
+pub struct DocumentDiagnostics {
+    pub version: Option<i32>,
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+/// The diagnostics of a document changed, they are empty once cleared
+///
+/// * `uri`: Uri of the document
+/// * `diagnostics`: The new diagnostics
+#[derive(Debug, Clone, PartialEq)]
+pub struct DiagnosticsChange {
+    pub uri: Uri,
+    pub diagnostics: DocumentDiagnostics,
+}
+
+/// Number of diagnostics per severity
+/// Diagnostics without severity are counted as errors, as most editors show them
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DiagnosticCounts {
+    pub errors: usize,
+    pub warnings: usize,
+    pub information: usize,
+    pub hints: usize,
+}
+
+impl DiagnosticCounts {
+    fn add(&mut self, diagnostics: &[Diagnostic]) {
+        for diagnostic in diagnostics {
+            match diagnostic.severity {
+                Some(DiagnosticSeverity::WARNING) => self.warnings += 1,
+                Some(DiagnosticSeverity::INFORMATION) => self.information += 1,
+                Some(DiagnosticSeverity::HINT) => self.hints += 1,
+                _ => self.errors += 1,
+            }
+        }
+    }
+
+    /// Number of diagnostics of every severity
+    pub fn total(&self) -> usize {
+        self.errors + self.warnings + self.information + self.hints
+    }
+}
+
+#[derive(Default)]
+struct State {
+    documents: HashMap<Uri, DocumentDiagnostics>,
+    subscribers: Vec<StreamSender<DiagnosticsChange>>,
+}
+
+/// The latest diagnostics published by the server, keyed by document
+/// `textDocument/publishDiagnostics` is handled from the start of the server, and the pulled
+/// diagnostics are stored as well, see [crate::process::LanguageServer::pull_diagnostics].
+/// Diagnostics of an older version than the stored ones are dropped
+///
+/// # Usage
+/// ```rust
+///     use futures::StreamExt;
+///
+///     let diagnostics = server.diagnostics();
+///     let counts = diagnostics.counts();
+///     let mut changes = diagnostics.changes();
+///     while let Some(change) = changes.next().await {
+///         ...
+///     }
+/// ```
+#[derive(Clone)]
+pub struct DiagnosticsStore {
+    state: Arc<Mutex<State>>,
+}
+
+impl DiagnosticsStore {
+    pub(crate) fn new(listener: &Listener) -> Self {
+        let state = Arc::new(Mutex::new(State::default()));
+
+        listener
+            .on_notification::<notification::PublishDiagnostics, _>({
+                let state = state.clone();
+                move |params| update(&state, params.uri, params.version, params.diagnostics)
+            })
+            .detach();
+
+        Self { state }
+    }
+
+    /// Latest diagnostics of a document, `None` if the server never published any
+    pub fn get(&self, uri: &Uri) -> Option<DocumentDiagnostics> {
+        self.state.lock().documents.get(uri).cloned()
+    }
+
+    /// Latest diagnostics of every document
+    pub fn all(&self) -> Vec<(Uri, DocumentDiagnostics)> {
+        self.state
+            .lock()
+            .documents
+            .iter()
+            .map(|(uri, document)| (uri.clone(), document.clone()))
+            .collect()
+    }
+
+    /// Diagnostics of one severity across every document
+    /// Diagnostics without severity are errors, see [DiagnosticCounts]
+    pub fn by_severity(&self, severity: DiagnosticSeverity) -> Vec<(Uri, Diagnostic)> {
+        self.state
+            .lock()
+            .documents
+            .iter()
+            .flat_map(|(uri, document)| {
+                document
+                    .diagnostics
+                    .iter()
+                    .filter(|diagnostic| {
+                        diagnostic.severity.unwrap_or(DiagnosticSeverity::ERROR) == severity
+                    })
+                    .map(|diagnostic| (uri.clone(), diagnostic.clone()))
+            })
+            .collect()
+    }
+
+    /// Number of diagnostics per severity across every document
+    pub fn counts(&self) -> DiagnosticCounts {
+        let mut counts = DiagnosticCounts::default();
+        for document in self.state.lock().documents.values() {
+            counts.add(&document.diagnostics);
+        }
+        counts
+    }
+
+    /// Number of diagnostics per severity of one document
+    pub fn document_counts(&self, uri: &Uri) -> DiagnosticCounts {
+        let mut counts = DiagnosticCounts::default();
+        if let Some(document) = self.state.lock().documents.get(uri) {
+            counts.add(&document.diagnostics);
+        }
+        counts
+    }
+
+    /// Receive the changes of the diagnostics as a stream
+    /// At most 64 changes are buffered, the oldest are dropped when the consumer lags behind
+    pub fn changes(&self) -> NotificationStream<DiagnosticsChange> {
+        self.changes_with(NOTIFICATION_STREAM_CAPACITY, LagPolicy::default())
+    }
+
+    /// Receive the changes of the diagnostics as a stream, see [DiagnosticsStore::changes]
+    ///
+    /// * `capacity`: Number of changes buffered
+    /// * `policy`: What to drop when the buffer is full, see [LagPolicy]
+    pub fn changes_with(
+        &self,
+        capacity: usize,
+        policy: LagPolicy,
+    ) -> NotificationStream<DiagnosticsChange> {
+        let (sender, stream) = stream::channel(capacity, policy);
+        self.state.lock().subscribers.push(sender);
+        stream
+    }
+
+    fn update(&self, uri: Uri, version: Option<i32>, diagnostics: Vec<Diagnostic>) {
+        update(&self.state, uri, version, diagnostics)
+    }
+
+    // The stored diagnostics still apply to this version of the document
+    fn set_version(&self, uri: &Uri, version: Option<i32>) {
+        if let Some(document) = self.state.lock().documents.get_mut(uri) {
+            if document
+                .version
+                .zip(version)
+                .is_none_or(|(stored, version)| version >= stored)
+            {
+                document.version = version;
+            }
+        }
+    }
+}
+
+// Store the diagnostics and notify the subscribers, unless they are older than the stored ones
+fn update(state: &Mutex<State>, uri: Uri, version: Option<i32>, diagnostics: Vec<Diagnostic>) {
+    let mut state = state.lock();
+
+    let stale = state
+        .documents
+        .get(&uri)
+        .and_then(|document| document.version)
+        .zip(version)
+        .is_some_and(|(stored, version)| version < stored);
+    if stale {
+        return;
+    }
+
+    let diagnostics = DocumentDiagnostics {
+        version,
+        diagnostics,
+    };
+
+    // Cleared documents are kept, to know the version of their diagnostics
+    state.documents.insert(uri.clone(), diagnostics.clone());
+
+    // Streams dropped by their consumer are forgotten
+    state.subscribers.retain(|sender| !sender.is_closed());
+    for sender in &state.subscribers {
+        sender.send(DiagnosticsChange {
+            uri: uri.clone(),
+            diagnostics: diagnostics.clone(),
+        });
+    }
+}
+
+// Result ids of the last reports, and versions of the documents the server knows
+#[derive(Default)]
+struct PullState {
+    result_ids: HashMap<Uri, String>,
+    versions: HashMap<Uri, i32>,
+}
+
+// Pull the diagnostics with `textDocument/diagnostic` and `workspace/diagnostic`, when the
+// server supports it, and store them in the [DiagnosticsStore]
+// The documents are pulled once the server knows their changes, and again on
+// `workspace/diagnostic/refresh`
+#[derive(Clone)]
+pub(crate) struct DiagnosticsPuller {
+    listener: Weak<Listener>,
+    capabilities: Arc<RwLock<ServerCapabilities>>,
+    store: DiagnosticsStore,
+    state: Arc<Mutex<PullState>>,
+    refresh: Arc<Notify>,
+}
+
+impl DiagnosticsPuller {
+    pub(crate) fn new(
+        listener: &Arc<Listener>,
+        capabilities: Arc<RwLock<ServerCapabilities>>,
+        store: DiagnosticsStore,
+        documents: &DocumentStore,
+    ) -> Self {
+        let puller = Self {
+            listener: Arc::downgrade(listener),
+            capabilities,
+            store,
+            state: Default::default(),
+            refresh: Default::default(),
+        };
+
+        documents.on_event(Box::new({
+            let puller = puller.clone();
+            move |event| puller.on_document_event(event)
+        }));
+
+        listener
+            .on_fallback_request::<request::WorkspaceDiagnosticRefresh, _, _, _>({
+                let puller = puller.clone();
+                move |_| {
+                    puller.refresh.notify_waiters();
+                    puller.pull_all();
+                    async { Ok(()) }
+                }
+            })
+            .detach();
+
+        // The server holds `workspace/diagnostic` until something changed
+        listener.update_timeouts(|timeouts| {
+            timeouts
+                .methods
+                .entry(request::WorkspaceDiagnosticRequest::METHOD)
+                .or_insert(None);
+        });
+
+        puller
+    }
+
+    fn options(&self) -> Option<DiagnosticOptions> {
+        match self.capabilities.read().diagnostic_provider.clone()? {
+            DiagnosticServerCapabilities::Options(options) => Some(options),
+            DiagnosticServerCapabilities::RegistrationOptions(options) => {
+                Some(options.diagnostic_options)
+            }
+        }
+    }
+
+    fn on_document_event(&self, event: DocumentEvent) {
+        match event {
+            DocumentEvent::Synced { uri, version } => {
+                self.state.lock().versions.insert(uri.clone(), version);
+
+                match self.options() {
+                    // Other documents may depend on this one
+                    Some(options) if options.inter_file_dependencies => self.pull_all(),
+                    Some(_) => self.spawn_pull(uri),
+                    None => {}
+                }
+            }
+            DocumentEvent::Closed { uri } => {
+                let mut state = self.state.lock();
+                state.versions.remove(&uri);
+                if state.result_ids.remove(&uri).is_some() {
+                    drop(state);
+                    self.store.update(uri, None, Vec::new());
+                }
+            }
+        }
+    }
+
+    // Pull every document the server knows, in the background
+    fn pull_all(&self) {
+        if self.options().is_none() {
+            return;
+        }
+
+        let uris = self
+            .state
+            .lock()
+            .versions
+            .keys()
+            .cloned()
+            .collect::<Vec<_>>();
+        for uri in uris {
+            self.spawn_pull(uri);
+        }
+    }
+
+    fn spawn_pull(&self, uri: Uri) {
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+
+        let puller = self.clone();
+        runtime.spawn(async move {
+            if let Err(error) = puller.pull(uri.clone()).await {
+                log::warn!(
+                    "Failed to pull the diagnostics of {}: {}",
+                    uri.as_str(),
+                    error
+                );
+            }
+        });
+    }
+
+    // Pull the diagnostics of a document, once more if the server asks for it
+    pub(crate) async fn pull(&self, uri: Uri) -> Result<(), Error> {
+        match self.pull_once(uri.clone()).await {
+            Err(error) if retrigger(&error) => self.pull_once(uri).await,
+            result => result,
+        }
+    }
+
+    async fn pull_once(&self, uri: Uri) -> Result<(), Error> {
+        let Some(options) = self.options() else {
+            return Ok(());
+        };
+
+        let (version, previous_result_id) = {
+            let state = self.state.lock();
+            (
+                state.versions.get(&uri).copied(),
+                state.result_ids.get(&uri).cloned(),
+            )
+        };
+
+        let listener = self.listener.upgrade().ok_or(Error::TransportClosed)?;
+        let (_, response) = listener.request_cancellable::<request::DocumentDiagnosticRequest>(
+            DocumentDiagnosticParams {
+                text_document: TextDocumentIdentifier { uri: uri.clone() },
+                identifier: options.identifier,
+                previous_result_id,
+                work_done_progress_params: Default::default(),
+                partial_result_params: Default::default(),
+            },
+        );
+        drop(listener);
+
+        let (report, related_documents) = match response.await? {
+            DocumentDiagnosticReportResult::Report(DocumentDiagnosticReport::Full(report)) => (
+                Some(DocumentDiagnosticReportKind::Full(
+                    report.full_document_diagnostic_report,
+                )),
+                report.related_documents,
+            ),
+            DocumentDiagnosticReportResult::Report(DocumentDiagnosticReport::Unchanged(report)) => {
+                (
+                    Some(DocumentDiagnosticReportKind::Unchanged(
+                        report.unchanged_document_diagnostic_report,
+                    )),
+                    report.related_documents,
+                )
+            }
+            DocumentDiagnosticReportResult::Partial(partial) => (None, partial.related_documents),
+        };
+
+        if let Some(report) = report {
+            self.apply(uri, version, report);
+        }
+
+        for (uri, report) in related_documents.into_iter().flatten() {
+            let version = self.state.lock().versions.get(&uri).copied();
+            self.apply(uri, version, report);
+        }
+
+        Ok(())
+    }
+
+    // Keep `workspace/diagnostic` running, until the task is aborted or the server is gone
+    // The request is sent again once answered, or right away on refresh
+    pub(crate) async fn poll_workspace(self) {
+        loop {
+            let Some(options) = self
+                .options()
+                .filter(|options| options.workspace_diagnostics)
+            else {
+                return;
+            };
+            let Some(listener) = self.listener.upgrade() else {
+                return;
+            };
+
+            let previous_result_ids = self
+                .state
+                .lock()
+                .result_ids
+                .iter()
+                .map(|(uri, value)| PreviousResultId {
+                    uri: uri.clone(),
+                    value: value.clone(),
+                })
+                .collect();
+
+            let mut reports = listener.request_streaming::<request::WorkspaceDiagnosticRequest>(
+                WorkspaceDiagnosticParams {
+                    identifier: options.identifier,
+                    previous_result_ids,
+                    work_done_progress_params: Default::default(),
+                    partial_result_params: Default::default(),
+                },
+            );
+            drop(listener);
+
+            let started = Instant::now();
+            let refresh = self.refresh.notified();
+            tokio::pin!(refresh);
+
+            // The final response holds the partial results too, they are only applied once
+            let mut applied = 0;
+            loop {
+                let report = select! {
+                    report = reports.next() => report,
+                    // Dropping the stream cancels the request
+                    _ = &mut refresh => break,
+                };
+
+                match report {
+                    Some(Ok(PartialResult::Partial(report))) => {
+                        let items = workspace_items(report);
+                        applied += items.len();
+                        self.apply_workspace(items);
+                    }
+                    Some(Ok(PartialResult::Done(report))) => {
+                        self.apply_workspace(workspace_items(report).into_iter().skip(applied));
+                    }
+                    Some(Err(error)) if retrigger(&error) || error.is_content_modified() => {}
+                    Some(Err(error)) => {
+                        log::warn!("Workspace diagnostics stopped: {}", error);
+                        return;
+                    }
+                    None => break,
+                }
+            }
+
+            tokio::time::sleep_until((started + WORKSPACE_DIAGNOSTIC_INTERVAL).into()).await;
+        }
+    }
+
+    fn apply_workspace(&self, items: impl IntoIterator<Item = WorkspaceDocumentDiagnosticReport>) {
+        for item in items {
+            match item {
+                WorkspaceDocumentDiagnosticReport::Full(report) => self.apply(
+                    report.uri,
+                    report.version.map(|version| version as i32),
+                    DocumentDiagnosticReportKind::Full(report.full_document_diagnostic_report),
+                ),
+                WorkspaceDocumentDiagnosticReport::Unchanged(report) => self.apply(
+                    report.uri,
+                    report.version.map(|version| version as i32),
+                    DocumentDiagnosticReportKind::Unchanged(
+                        report.unchanged_document_diagnostic_report,
+                    ),
+                ),
+            }
+        }
+    }
+
+    fn apply(&self, uri: Uri, version: Option<i32>, report: DocumentDiagnosticReportKind) {
+        match report {
+            DocumentDiagnosticReportKind::Full(report) => {
+                let mut state = self.state.lock();
+                match report.result_id {
+                    Some(result_id) => state.result_ids.insert(uri.clone(), result_id),
+                    None => state.result_ids.remove(&uri),
+                };
+                drop(state);
+
+                self.store.update(uri, version, report.items);
+            }
+            DocumentDiagnosticReportKind::Unchanged(report) => {
+                self.state
+                    .lock()
+                    .result_ids
+                    .insert(uri.clone(), report.result_id);
+                self.store.set_version(&uri, version);
+            }
+        }
+    }
+}
+
+fn workspace_items(
+    report: WorkspaceDiagnosticReportResult,
+) -> Vec<WorkspaceDocumentDiagnosticReport> {
+    match report {
+        WorkspaceDiagnosticReportResult::Report(report) => report.items,
+        WorkspaceDiagnosticReportResult::Partial(partial) => partial.items,
+    }
+}
+
+// Whether the server cancelled the request and asks for it to be sent again
+fn retrigger(error: &Error) -> bool {
+    let Error::Response(response) = error else {
+        return false;
+    };
+
+    error.is_server_cancelled()
+        && response
+            .data
+            .clone()
+            .and_then(|data| serde_json::from_value::<DiagnosticServerCancellationData>(data).ok())
+            .unwrap_or_default()
+            .retrigger_request
+}
@@ -0,0 +1,256 @@
+use lsp_types::{PositionEncodingKind, Range, TextDocumentContentChangeEvent};
+
+use crate::encoding;
+
+// Size of the table compared line by line, bigger differences are sent as one change
+const MAX_DIFF_CELLS: usize = 1 << 20;
+
+// A replaced part of the text, in byte ranges of the old and new text
+struct Hunk {
+    old: std::ops::Range<usize>,
+    new: std::ops::Range<usize>,
+}
+
+/// The ranged changes turning `old` into `new`, for a `didChange` to a server supporting
+/// incremental synchronization
+/// The common start and end of the texts are skipped, what remains is compared line by line.
+/// The changes are ordered from the end of the document to its start, so the range of every
+/// change is still valid once the previous ones are applied, as the specification requires
+///
+/// # Usage
+/// ```rust
+///     use chan_rs::lsp_types::PositionEncodingKind;
+///
+///     let changes = diff::content_changes("let a = 1;\n", "let b = 1;\n", &PositionEncodingKind::UTF16);
+///     assert_eq!(changes.len(), 1);
+///     assert_eq!(changes[0].text, "b");
+/// ```
+/// * `old`: Text known by the server
+/// * `new`: Text to send
+/// * `encoding`: Encoding of the ranges, see [crate::process::LanguageServer::position_encoding]
+pub fn content_changes(
+    old: &str,
+    new: &str,
+    encoding: &PositionEncodingKind,
+) -> Vec<TextDocumentContentChangeEvent> {
+    let prefix = common_prefix(old, new);
+    let suffix = common_suffix(&old[prefix..], &new[prefix..]);
+
+    let old_middle = &old[prefix..old.len() - suffix];
+    let new_middle = &new[prefix..new.len() - suffix];
+    if old_middle.is_empty() && new_middle.is_empty() {
+        return Vec::new();
+    }
+
+    hunks(old_middle, new_middle)
+        .into_iter()
+        .rev()
+        .map(|hunk| TextDocumentContentChangeEvent {
+            range: Some(Range {
+                start: encoding::offset_to_position(old, prefix + hunk.old.start, encoding),
+                end: encoding::offset_to_position(old, prefix + hunk.old.end, encoding),
+            }),
+            range_length: None,
+            text: new_middle[hunk.new].to_string(),
+        })
+        .collect()
+}
+
+// Length of the common start of the texts, on a char boundary outside of a `\r\n` of the old
+// text, a position can't point between them
+fn common_prefix(old: &str, new: &str) -> usize {
+    let prefix = old
+        .char_indices()
+        .zip(new.chars())
+        .find(|((_, old), new)| old != new)
+        .map_or(old.len().min(new.len()), |((offset, _), _)| offset);
+
+    match old[..prefix].ends_with('\r') && old[prefix..].starts_with('\n') {
+        true => prefix - 1,
+        false => prefix,
+    }
+}
+
+// Length of the common end of the texts, on a char boundary outside of a `\r\n` of the old text
+fn common_suffix(old: &str, new: &str) -> usize {
+    let suffix = old
+        .chars()
+        .rev()
+        .zip(new.chars().rev())
+        .take_while(|(old, new)| old == new)
+        .map(|(char, _)| char.len_utf8())
+        .sum::<usize>();
+
+    let start = old.len() - suffix;
+    match old[..start].ends_with('\r') && old[start..].starts_with('\n') {
+        true => suffix - 1,
+        false => suffix,
+    }
+}
+
+// Lines of the text with their line break: `\n`, `\r\n` or a lone `\r`
+fn lines(text: &str) -> Vec<&str> {
+    let bytes = text.as_bytes();
+    let mut lines = Vec::new();
+    let mut start = 0;
+
+    for (i, &byte) in bytes.iter().enumerate() {
+        if byte == b'\n' || (byte == b'\r' && bytes.get(i + 1) != Some(&b'\n')) {
+            lines.push(&text[start..=i]);
+            start = i + 1;
+        }
+    }
+    if start < text.len() {
+        lines.push(&text[start..]);
+    }
+
+    lines
+}
+
+// Replaced lines, from the longest common subsequence of the lines of the texts
+fn hunks(old: &str, new: &str) -> Vec<Hunk> {
+    let old_lines = lines(old);
+    let new_lines = lines(new);
+    let (n, m) = (old_lines.len(), new_lines.len());
+
+    if (n + 1).saturating_mul(m + 1) > MAX_DIFF_CELLS {
+        return vec![Hunk {
+            old: 0..old.len(),
+            new: 0..new.len(),
+        }];
+    }
+
+    // `lcs[i][j]`: Length of the common subsequence of `old_lines[i..]` and `new_lines[j..]`
+    let mut lcs = vec![0u32; (n + 1) * (m + 1)];
+    for i in (0..n).rev() {
+        for j in (0..m).rev() {
+            lcs[i * (m + 1) + j] = if old_lines[i] == new_lines[j] {
+                lcs[(i + 1) * (m + 1) + j + 1] + 1
+            } else {
+                lcs[(i + 1) * (m + 1) + j].max(lcs[i * (m + 1) + j + 1])
+            };
+        }
+    }
+
+    let mut hunks = Vec::<Hunk>::new();
+    let (mut i, mut j) = (0, 0);
+    let (mut old_offset, mut new_offset) = (0, 0);
+
+    while i < n || j < m {
+        if i < n && j < m && old_lines[i] == new_lines[j] {
+            old_offset += old_lines[i].len();
+            new_offset += new_lines[j].len();
+            i += 1;
+            j += 1;
+            continue;
+        }
+
+        let (old_start, new_start) = (old_offset, new_offset);
+        if j == m || (i < n && lcs[(i + 1) * (m + 1) + j] >= lcs[i * (m + 1) + j + 1]) {
+            old_offset += old_lines[i].len();
+            i += 1;
+        } else {
+            new_offset += new_lines[j].len();
+            j += 1;
+        }
+
+        // Consecutive removed and inserted lines are one change
+        match hunks.last_mut() {
+            Some(hunk) if hunk.old.end == old_start && hunk.new.end == new_start => {
+                hunk.old.end = old_offset;
+                hunk.new.end = new_offset;
+            }
+            _ => hunks.push(Hunk {
+                old: old_start..old_offset,
+                new: new_start..new_offset,
+            }),
+        }
+    }
+
+    hunks
+}
+
+#[cfg(test)]
+mod tests {
+    use lsp_types::PositionEncodingKind;
+
+    use super::content_changes;
+    use crate::utils;
+
+    // Apply the changes to `old` in every encoding, the result must be `new`
+    fn assert_roundtrip(old: &str, new: &str) {
+        for encoding in [
+            PositionEncodingKind::UTF8,
+            PositionEncodingKind::UTF16,
+            PositionEncodingKind::UTF32,
+        ] {
+            let mut text = old.to_string();
+            for change in content_changes(old, new, &encoding) {
+                utils::apply_change(&mut text, change, &encoding);
+            }
+            assert_eq!(text, new, "{:?} -> {:?} in {:?}", old, new, encoding);
+        }
+    }
+
+    #[test]
+    fn identical_texts_have_no_change() {
+        let changes = content_changes("a\nb\n", "a\nb\n", &PositionEncodingKind::UTF16);
+        assert!(changes.is_empty());
+    }
+
+    #[test]
+    fn insert_and_delete_at_the_edges() {
+        assert_roundtrip("", "abc");
+        assert_roundtrip("abc", "");
+        assert_roundtrip("b\nc", "a\nb\nc");
+        assert_roundtrip("a\nb\nc", "b\nc");
+        assert_roundtrip("a\nb", "a\nb\nc\n");
+        assert_roundtrip("a\nb\nc\n", "a\nb");
+    }
+
+    #[test]
+    fn crlf_is_never_split() {
+        assert_roundtrip("a\r\nb", "a\rb");
+        assert_roundtrip("a\rb", "a\r\nb");
+        assert_roundtrip("a\r\nb", "a\nb");
+        assert_roundtrip("a\nb", "a\r\nb");
+        assert_roundtrip("a\r\n", "a\r");
+        assert_roundtrip("a\r", "a\r\n");
+        assert_roundtrip("a\r\nb\r\n", "a\r\nx\r\nb\r\n");
+        assert_roundtrip("one\r\ntwo\r\nthree", "one\r\n2\r\nthree\r\n");
+    }
+
+    #[test]
+    fn lone_cr_ends_a_line() {
+        let changes = content_changes("a\rb\rc", "a\rB\rc", &PositionEncodingKind::UTF16);
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].text, "B");
+
+        let range = changes[0].range.unwrap();
+        assert_eq!((range.start.line, range.start.character), (1, 0));
+        assert_eq!((range.end.line, range.end.character), (1, 1));
+
+        assert_roundtrip("a\rb\rc", "a\rx\rb\rc");
+        assert_roundtrip("a\rb\rc", "a\r\nb\nc");
+        assert_roundtrip("a\r\nb", "a\r\rb");
+    }
+
+    #[test]
+    fn multibyte_chars() {
+        assert_roundtrip("héllo", "hèllo");
+        assert_roundtrip("a😀b", "a😁b");
+        assert_roundtrip("😀\n日本\n", "😀\n日本語\n");
+        assert_roundtrip("x\n日本\ny", "x\ny\n日本");
+    }
+
+    #[test]
+    fn ranges_of_a_single_change() {
+        let changes = content_changes("let a = 1;\n", "let b = 1;\n", &PositionEncodingKind::UTF16);
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].text, "b");
+
+        let range = changes[0].range.unwrap();
+        assert_eq!((range.start.line, range.start.character), (0, 4));
+        assert_eq!((range.end.line, range.end.character), (0, 5));
+    }
+}
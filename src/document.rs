@@ -0,0 +1,618 @@
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use lsp_types::{
+    notification, DidChangeTextDocumentParams, DidCloseTextDocumentParams,
+    DidOpenTextDocumentParams, DidSaveTextDocumentParams, Position, PositionEncodingKind, Range,
+    ServerCapabilities, TextDocumentContentChangeEvent, TextDocumentIdentifier, TextDocumentItem,
+    TextDocumentSyncCapability, TextDocumentSyncKind, TextDocumentSyncSaveOptions, Uri,
+    VersionedTextDocumentIdentifier,
+};
+use parking_lot::{Mutex, RwLock};
+
+use crate::{diff, encoding, listener::Notifier, utils, Error};
+
+// How the server wants the documents to be synchronized
+//
+// * `open_close`: Send `didOpen` and `didClose`
+// * `change`: Send `didChange` with the whole text, the edited ranges, or not at all
+// * `save`: Send `didSave`, including the text if it is true
+struct SyncOptions {
+    open_close: bool,
+    change: TextDocumentSyncKind,
+    save: Option<bool>,
+}
+
+impl SyncOptions {
+    fn new(capabilities: &ServerCapabilities) -> Self {
+        match &capabilities.text_document_sync {
+            // A kind alone implies every notification, as vscode does
+            Some(TextDocumentSyncCapability::Kind(kind)) => Self {
+                open_close: true,
+                change: *kind,
+                save: Some(false),
+            },
+            Some(TextDocumentSyncCapability::Options(options)) => Self {
+                open_close: options.open_close.unwrap_or_default(),
+                change: options.change.unwrap_or(TextDocumentSyncKind::NONE),
+                save: match &options.save {
+                    Some(TextDocumentSyncSaveOptions::Supported(true)) => Some(false),
+                    Some(TextDocumentSyncSaveOptions::SaveOptions(options)) => {
+                        Some(options.include_text.unwrap_or_default())
+                    }
+                    _ => None,
+                },
+            },
+            None => Self {
+                open_close: false,
+                change: TextDocumentSyncKind::NONE,
+                save: None,
+            },
+        }
+    }
+}
+
+/// The documents opened on the server, with their text and version
+/// `didOpen`, `didChange`, `didClose` and `didSave` are sent as the [ServerCapabilities] of the
+/// server ask: not at all, with the whole text, or with the edited ranges only.
+/// Versions start at 1 and are incremented on every change
+///
+/// Changes can be coalesced, see [DocumentStore::set_coalesce_window]
+///
+/// # Usage
+/// ```rust
+///     let documents = server.documents();
+///     documents.open(uri.clone(), "rust", "fn main() {}")?;
+///     documents.edit(&uri, range, "println!()")?;
+///     documents.close(&uri)?;
+/// ```
+#[derive(Clone)]
+pub struct DocumentStore {
+    state: Arc<Mutex<State>>,
+    capabilities: Arc<RwLock<ServerCapabilities>>,
+    notifier: Notifier,
+}
+
+// The open documents, and their changes not sent yet
+//
+// * `documents`: Open documents, with the text and version of the last change
+// * `pending`: Ranged changes not sent yet, keyed by document
+// * `coalesce`: Time changes are held before being sent, `None` send them right away
+// * `hooks`: Called once the server knows about a document event
+#[derive(Default)]
+struct State {
+    documents: HashMap<Uri, TextDocumentItem>,
+    pending: HashMap<Uri, Vec<TextDocumentContentChangeEvent>>,
+    coalesce: Option<Duration>,
+    hooks: Vec<DocumentHook>,
+}
+
+// What the server was told about a document
+//
+// * `Synced`: The server knows the content of this version of the document
+// * `Closed`: The document was closed
+#[derive(Debug, Clone)]
+pub(crate) enum DocumentEvent {
+    Synced { uri: Uri, version: i32 },
+    Closed { uri: Uri },
+}
+
+// Hook called with every document event, under the lock of the store
+pub(crate) type DocumentHook = Box<dyn Send + FnMut(DocumentEvent)>;
+
+fn call_hooks(hooks: &mut [DocumentHook], event: DocumentEvent) {
+    for hook in hooks {
+        hook(event.clone())
+    }
+}
+
+impl State {
+    // Send the pending changes of the document as one `didChange`
+    fn flush(
+        &mut self,
+        uri: &Uri,
+        kind: TextDocumentSyncKind,
+        notifier: &Notifier,
+    ) -> Result<(), Error> {
+        let Some(changes) = self.pending.remove(uri) else {
+            return Ok(());
+        };
+
+        let Some(document) = self.documents.get(uri) else {
+            return Ok(());
+        };
+
+        // Hold the changes again, the next flush sends them
+        let sent = send_changes(notifier, &mut self.hooks, document, kind, changes.clone());
+        if sent.is_err() {
+            self.pending.insert(uri.clone(), changes);
+        }
+
+        sent
+    }
+}
+
+impl DocumentStore {
+    pub(crate) fn new(capabilities: Arc<RwLock<ServerCapabilities>>, notifier: Notifier) -> Self {
+        Self {
+            state: Default::default(),
+            capabilities,
+            notifier,
+        }
+    }
+
+    /// Hold the changes of a document for `window` after its first edit, then send them as
+    /// one `didChange`. Useful to avoid flooding slow servers with a change per keystroke.
+    /// The changes are sent before any request or notification of the [LanguageServer], so the
+    /// server never answers on a stale document. `None`, the default, sends every change right
+    /// away and sends the changes being held
+    ///
+    /// # Usage
+    /// ```rust
+    ///     server.documents().set_coalesce_window(Some(Duration::from_millis(50)))?;
+    /// ```
+    /// * `window`: Time the changes are held
+    ///
+    /// [LanguageServer]: crate::process::LanguageServer
+    pub fn set_coalesce_window(&self, window: Option<Duration>) -> Result<(), Error> {
+        self.state.lock().coalesce = window;
+
+        match window {
+            Some(_) => Ok(()),
+            None => self.flush_all(),
+        }
+    }
+
+    // Register a hook called with the document events, for the lifetime of the server
+    pub(crate) fn on_event(&self, hook: DocumentHook) {
+        self.state.lock().hooks.push(hook);
+    }
+
+    /// Send the changes of a document held by [DocumentStore::set_coalesce_window]
+    pub fn flush(&self, uri: &Uri) -> Result<(), Error> {
+        let kind = self.sync_options().change;
+        self.state.lock().flush(uri, kind, &self.notifier)
+    }
+
+    /// Send the changes of every document held by [DocumentStore::set_coalesce_window]
+    pub fn flush_all(&self) -> Result<(), Error> {
+        let kind = self.sync_options().change;
+        let mut state = self.state.lock();
+
+        let uris = state.pending.keys().cloned().collect::<Vec<_>>();
+        for uri in uris {
+            state.flush(&uri, kind, &self.notifier)?;
+        }
+
+        Ok(())
+    }
+
+    /// Open a document, sending `didOpen`
+    /// Fails with [Error::DocumentAlreadyOpen] if it is already open, and with
+    /// [Error::ServerNotInitialized] until the handshake is done: the capabilities of the server
+    /// aren't known yet, the server would never hear of the document
+    ///
+    /// * `uri`: Uri of the document
+    /// * `language_id`: Language of the document, eg. "rust"
+    /// * `text`: Content of the document
+    pub fn open(
+        &self,
+        uri: Uri,
+        language_id: impl Into<String>,
+        text: impl Into<String>,
+    ) -> Result<(), Error> {
+        self.reopen(TextDocumentItem {
+            uri,
+            language_id: language_id.into(),
+            version: 1,
+            text: text.into(),
+        })
+    }
+
+    // Open a document with its version, the previous server of a supervisor may have known it
+    pub(crate) fn reopen(&self, document: TextDocumentItem) -> Result<(), Error> {
+        self.notifier.check_state()?;
+
+        let uri = document.uri.clone();
+        let mut state = self.state.lock();
+        if state.documents.contains_key(&uri) {
+            return Err(Error::DocumentAlreadyOpen(uri));
+        }
+
+        if self.sync_options().open_close {
+            self.notifier
+                .notify_checked::<notification::DidOpenTextDocument>(DidOpenTextDocumentParams {
+                    text_document: document.clone(),
+                })?;
+
+            call_hooks(
+                &mut state.hooks,
+                DocumentEvent::Synced {
+                    uri: uri.clone(),
+                    version: document.version,
+                },
+            );
+        }
+
+        state.documents.insert(uri, document);
+        Ok(())
+    }
+
+    /// Replace a range of a document, sending `didChange`
+    ///
+    /// * `uri`: Uri of the document
+    /// * `range`: Range to replace, in the negotiated position encoding
+    /// * `text`: New text of the range
+    pub fn edit(&self, uri: &Uri, range: Range, text: impl Into<String>) -> Result<(), Error> {
+        let change = TextDocumentContentChangeEvent {
+            range: Some(range),
+            range_length: None,
+            text: text.into(),
+        };
+
+        self.change(uri, |_, _, _| vec![change])
+    }
+
+    /// Replace the whole content of a document, sending `didChange`
+    /// Servers supporting incremental synchronization only get the changed ranges, see
+    /// [diff::content_changes]. Nothing is sent if the content is the same
+    ///
+    /// * `uri`: Uri of the document
+    /// * `text`: New content of the document
+    pub fn replace_all(&self, uri: &Uri, text: impl Into<String>) -> Result<(), Error> {
+        let text = text.into();
+
+        self.change(uri, |old, encoding, kind| match kind {
+            TextDocumentSyncKind::INCREMENTAL => diff::content_changes(old, &text, encoding),
+            _ if old == text => Vec::new(),
+            _ => vec![TextDocumentContentChangeEvent {
+                range: None,
+                range_length: None,
+                text,
+            }],
+        })
+    }
+
+    /// Close a document, sending `didClose`
+    /// The changes being held are dropped, the server forgets the document anyway
+    pub fn close(&self, uri: &Uri) -> Result<(), Error> {
+        let mut state = self.state.lock();
+        if !state.documents.contains_key(uri) {
+            return Err(Error::DocumentNotOpen(uri.clone()));
+        }
+
+        if self.sync_options().open_close {
+            self.notifier
+                .notify_checked::<notification::DidCloseTextDocument>(
+                    DidCloseTextDocumentParams {
+                        text_document: TextDocumentIdentifier { uri: uri.clone() },
+                    },
+                )?;
+        }
+
+        state.pending.remove(uri);
+        state.documents.remove(uri);
+        call_hooks(&mut state.hooks, DocumentEvent::Closed { uri: uri.clone() });
+
+        Ok(())
+    }
+
+    /// Tell the server the document was saved, sending `didSave` with the text if it asks for it
+    /// The changes being held are sent first
+    pub fn save(&self, uri: &Uri) -> Result<(), Error> {
+        let sync = self.sync_options();
+        let mut state = self.state.lock();
+        if !state.documents.contains_key(uri) {
+            return Err(Error::DocumentNotOpen(uri.clone()));
+        }
+
+        let Some(include_text) = sync.save else {
+            return Ok(());
+        };
+
+        state.flush(uri, sync.change, &self.notifier)?;
+        let document = &state.documents[uri];
+
+        self.notifier
+            .notify_checked::<notification::DidSaveTextDocument>(DidSaveTextDocumentParams {
+                text_document: TextDocumentIdentifier { uri: uri.clone() },
+                text: include_text.then(|| document.text.clone()),
+            })
+    }
+
+    /// An open document, with its current text and version
+    pub fn get(&self, uri: &Uri) -> Option<TextDocumentItem> {
+        self.state.lock().documents.get(uri).cloned()
+    }
+
+    // Language of an open document
+    pub(crate) fn language_id(&self, uri: &Uri) -> Option<String> {
+        self.state
+            .lock()
+            .documents
+            .get(uri)
+            .map(|document| document.language_id.clone())
+    }
+
+    /// Whether the document is open
+    pub fn is_open(&self, uri: &Uri) -> bool {
+        self.state.lock().documents.contains_key(uri)
+    }
+
+    /// Uris of the open documents
+    pub fn uris(&self) -> Vec<Uri> {
+        self.state.lock().documents.keys().cloned().collect()
+    }
+
+    /// Byte offset of a position in an open document, see [encoding::position_to_offset]
+    ///
+    /// * `uri`: Uri of the document
+    /// * `position`: Position in the negotiated position encoding
+    pub fn position_to_offset(&self, uri: &Uri, position: Position) -> Option<usize> {
+        let encoding = self.position_encoding();
+        let state = self.state.lock();
+        let document = state.documents.get(uri)?;
+
+        Some(encoding::position_to_offset(
+            &document.text,
+            position,
+            &encoding,
+        ))
+    }
+
+    /// Position of a byte offset in an open document, in the negotiated position encoding
+    /// See [encoding::offset_to_position]
+    ///
+    /// * `uri`: Uri of the document
+    /// * `offset`: Byte offset in the text of the document
+    pub fn offset_to_position(&self, uri: &Uri, offset: usize) -> Option<Position> {
+        let encoding = self.position_encoding();
+        let state = self.state.lock();
+        let document = state.documents.get(uri)?;
+
+        Some(encoding::offset_to_position(
+            &document.text,
+            offset,
+            &encoding,
+        ))
+    }
+
+    // Apply the changes made to the text and send them, as a whole text if the server doesn't
+    // support ranges. Ranged changes are held if they are coalesced
+    fn change(
+        &self,
+        uri: &Uri,
+        changes: impl FnOnce(
+            &str,
+            &PositionEncodingKind,
+            TextDocumentSyncKind,
+        ) -> Vec<TextDocumentContentChangeEvent>,
+    ) -> Result<(), Error> {
+        let kind = self.sync_options().change;
+        let encoding = self.position_encoding();
+
+        let mut state = self.state.lock();
+        let State {
+            documents,
+            pending,
+            coalesce,
+            hooks,
+        } = &mut *state;
+
+        let document = documents
+            .get_mut(uri)
+            .ok_or_else(|| Error::DocumentNotOpen(uri.clone()))?;
+
+        let changes = changes(&document.text, &encoding, kind);
+        if changes.is_empty() {
+            return Ok(());
+        }
+
+        let mut edited = document.clone();
+        for change in &changes {
+            utils::apply_change(&mut edited.text, change.clone(), &encoding);
+        }
+        edited.version += 1;
+
+        if kind == TextDocumentSyncKind::NONE {
+            *document = edited;
+            return Ok(());
+        }
+
+        // The document is left untouched if the server couldn't be told about the change
+        let (Some(window), Ok(runtime)) = (*coalesce, tokio::runtime::Handle::try_current()) else {
+            send_changes(&self.notifier, hooks, &edited, kind, changes)?;
+            *document = edited;
+            return Ok(());
+        };
+
+        *document = edited;
+
+        let held = pending.entry(uri.clone()).or_default();
+        let first = held.is_empty();
+        for change in changes {
+            coalesce_change(held, change, &encoding);
+        }
+
+        if first {
+            let state = Arc::downgrade(&self.state);
+            let capabilities = self.capabilities.clone();
+            let notifier = self.notifier.clone();
+            let uri = uri.clone();
+
+            runtime.spawn(async move {
+                tokio::time::sleep(window).await;
+                let Some(state) = state.upgrade() else {
+                    return;
+                };
+
+                let kind = SyncOptions::new(&capabilities.read()).change;
+                let flushed = state.lock().flush(&uri, kind, &notifier);
+                if let Err(error) = flushed {
+                    log::warn!("Failed to send the changes of {}: {}", uri.as_str(), error);
+                }
+            });
+        }
+
+        Ok(())
+    }
+
+    fn position_encoding(&self) -> PositionEncodingKind {
+        encoding::negotiated(&self.capabilities.read())
+    }
+
+    fn sync_options(&self) -> SyncOptions {
+        SyncOptions::new(&self.capabilities.read())
+    }
+}
+
+// Send the changes of the document, as a whole text if the server doesn't support ranges
+fn send_changes(
+    notifier: &Notifier,
+    hooks: &mut [DocumentHook],
+    document: &TextDocumentItem,
+    kind: TextDocumentSyncKind,
+    changes: Vec<TextDocumentContentChangeEvent>,
+) -> Result<(), Error> {
+    let content_changes = match kind {
+        TextDocumentSyncKind::INCREMENTAL => changes,
+        TextDocumentSyncKind::FULL => vec![TextDocumentContentChangeEvent {
+            range: None,
+            range_length: None,
+            text: document.text.clone(),
+        }],
+        _ => return Ok(()),
+    };
+
+    notifier.notify_checked::<notification::DidChangeTextDocument>(
+        DidChangeTextDocumentParams {
+            text_document: VersionedTextDocumentIdentifier {
+                uri: document.uri.clone(),
+                version: document.version,
+            },
+            content_changes,
+        },
+    )?;
+
+    call_hooks(
+        hooks,
+        DocumentEvent::Synced {
+            uri: document.uri.clone(),
+            version: document.version,
+        },
+    );
+
+    Ok(())
+}
+
+// Add a change to the held ones. The changes are applied in order by the server, an insertion
+// right after the text inserted by the last change, as when typing, extends the last change
+fn coalesce_change(
+    changes: &mut Vec<TextDocumentContentChangeEvent>,
+    change: TextDocumentContentChangeEvent,
+    encoding: &PositionEncodingKind,
+) {
+    if let (Some(last), Some(range)) = (changes.last_mut(), change.range) {
+        if let Some(last_range) = last.range {
+            let inserted = encoding::offset_to_position(&last.text, last.text.len(), encoding);
+            let end = match inserted.line {
+                0 => Position::new(
+                    last_range.start.line,
+                    last_range.start.character + inserted.character,
+                ),
+                lines => Position::new(last_range.start.line + lines, inserted.character),
+            };
+
+            if range.start == range.end && range.start == end {
+                last.text.push_str(&change.text);
+                return;
+            }
+        }
+    }
+
+    changes.push(change)
+}
+
+#[cfg(test)]
+mod tests {
+    use lsp_types::{Position, PositionEncodingKind, Range, TextDocumentContentChangeEvent};
+
+    use super::coalesce_change;
+    use crate::utils;
+
+    fn insert(line: u32, character: u32, text: &str) -> TextDocumentContentChangeEvent {
+        replace((line, character), (line, character), text)
+    }
+
+    fn replace(start: (u32, u32), end: (u32, u32), text: &str) -> TextDocumentContentChangeEvent {
+        TextDocumentContentChangeEvent {
+            range: Some(Range::new(
+                Position::new(start.0, start.1),
+                Position::new(end.0, end.1),
+            )),
+            range_length: None,
+            text: text.to_string(),
+        }
+    }
+
+    // Applying the coalesced changes must give the text of applying the changes one by one.
+    // Returns the number of coalesced changes
+    fn assert_coalesced(text: &str, changes: &[TextDocumentContentChangeEvent]) -> usize {
+        let encoding = PositionEncodingKind::UTF16;
+
+        let mut expected = text.to_string();
+        let mut coalesced = Vec::new();
+        for change in changes {
+            utils::apply_change(&mut expected, change.clone(), &encoding);
+            coalesce_change(&mut coalesced, change.clone(), &encoding);
+        }
+
+        let mut actual = text.to_string();
+        for change in coalesced.iter().cloned() {
+            utils::apply_change(&mut actual, change, &encoding);
+        }
+        assert_eq!(actual, expected);
+
+        coalesced.len()
+    }
+
+    #[test]
+    fn typing_is_one_change() {
+        let changes = [insert(0, 3, "a"), insert(0, 4, "b"), insert(0, 5, "c")];
+        assert_eq!(assert_coalesced("fn main", &changes), 1);
+    }
+
+    #[test]
+    fn typing_after_a_replacement() {
+        let changes = [replace((0, 0), (0, 2), "xyz"), insert(0, 3, "!")];
+        assert_eq!(assert_coalesced("fn main", &changes), 1);
+    }
+
+    #[test]
+    fn typing_new_lines() {
+        let changes = [
+            insert(1, 2, "a\n"),
+            insert(2, 0, "bc"),
+            insert(2, 2, "\r\n"),
+            insert(3, 0, "😀"),
+            insert(3, 2, "d"),
+        ];
+        assert_eq!(assert_coalesced("one\ntwo\nthree", &changes), 1);
+    }
+
+    #[test]
+    fn typing_multibyte_chars() {
+        let changes = [insert(0, 1, "😀"), insert(0, 3, "日本"), insert(0, 5, "x")];
+        assert_eq!(assert_coalesced("ab", &changes), 1);
+    }
+
+    #[test]
+    fn other_edits_are_kept_apart() {
+        // Elsewhere in the document
+        let changes = [insert(0, 0, "a"), insert(1, 0, "b"), insert(0, 0, "c")];
+        assert_eq!(assert_coalesced("x\ny", &changes), 3);
+
+        // Deleting after typing
+        let changes = [insert(0, 1, "ab\ncd"), replace((1, 1), (1, 2), "")];
+        assert_eq!(assert_coalesced("x\ny", &changes), 2);
+    }
+}
@@ -0,0 +1,246 @@
+use lsp_types::{Position, PositionEncodingKind, ServerCapabilities};
+
+// Encoding used by the server, UTF-16 unless it picked another one during the handshake
+pub(crate) fn negotiated(capabilities: &ServerCapabilities) -> PositionEncodingKind {
+    capabilities
+        .position_encoding
+        .clone()
+        .unwrap_or(PositionEncodingKind::UTF16)
+}
+
+// Length of a char in code units of the encoding, unknown encodings count UTF-16 code units
+fn char_len(encoding: &PositionEncodingKind) -> fn(char) -> usize {
+    if *encoding == PositionEncodingKind::UTF8 {
+        char::len_utf8
+    } else if *encoding == PositionEncodingKind::UTF32 {
+        |_| 1
+    } else {
+        char::len_utf16
+    }
+}
+
+// Start and end of the first line break at or after `from`: `\n`, `\r\n` or a lone `\r`
+fn next_line_break(text: &str, from: usize) -> Option<(usize, usize)> {
+    let start = from + text[from..].find(['\r', '\n'])?;
+    match text[start..].starts_with("\r\n") {
+        true => Some((start, start + 2)),
+        false => Some((start, start + 1)),
+    }
+}
+
+// Largest char boundary of the text at or before the offset
+fn floor_boundary(text: &str, offset: usize) -> usize {
+    let mut offset = offset.min(text.len());
+    while !text.is_char_boundary(offset) {
+        offset -= 1;
+    }
+    offset
+}
+
+/// Byte offset of a position in the text
+/// Lines end with `\n`, `\r\n` or `\r`. Positions past the end of their line are clamped to
+/// the end of the line, before its line break, lines past the end of the text to the end of the
+/// text
+///
+/// # Usage
+/// ```rust
+///     use chan_rs::lsp_types::{Position, PositionEncodingKind};
+///
+///     let offset = encoding::position_to_offset("a😀b", Position::new(0, 3), &PositionEncodingKind::UTF16);
+///     assert_eq!(offset, 5);
+/// ```
+/// * `text`: Content of the document
+/// * `position`: Position, in code units of the encoding
+/// * `encoding`: Encoding of the position, see [crate::process::LanguageServer::position_encoding]
+pub fn position_to_offset(
+    text: &str,
+    position: Position,
+    encoding: &PositionEncodingKind,
+) -> usize {
+    let mut line_start = 0;
+    for _ in 0..position.line {
+        match next_line_break(text, line_start) {
+            Some((_, end)) => line_start = end,
+            None => return text.len(),
+        }
+    }
+
+    let line_end = next_line_break(text, line_start).map_or(text.len(), |(start, _)| start);
+    let line = &text[line_start..line_end];
+
+    let char_len = char_len(encoding);
+    let mut units = 0;
+    for (offset, char) in line.char_indices() {
+        if units >= position.character as usize {
+            return line_start + offset;
+        }
+        units += char_len(char);
+    }
+
+    line_start + line.len()
+}
+
+/// Position of a byte offset in the text
+/// Offsets inside a char are moved back to the start of the char, offsets inside a `\r\n` to
+/// the end of the line
+///
+/// * `text`: Content of the document
+/// * `offset`: Byte offset, clamped to the length of the text
+/// * `encoding`: Encoding of the position, see [crate::process::LanguageServer::position_encoding]
+pub fn offset_to_position(text: &str, offset: usize, encoding: &PositionEncodingKind) -> Position {
+    let mut offset = floor_boundary(text, offset);
+    let (mut line, mut line_start) = (0, 0);
+    while let Some((start, end)) = next_line_break(text, line_start) {
+        if end > offset {
+            offset = offset.min(start);
+            break;
+        }
+        line += 1;
+        line_start = end;
+    }
+
+    let char_len = char_len(encoding);
+    let character = text[line_start..offset]
+        .chars()
+        .map(char_len)
+        .sum::<usize>();
+
+    Position::new(line, character as u32)
+}
+
+/// Byte offset of the char at a char offset in the text, the length of the text if it is past
+/// the end
+pub fn char_to_offset(text: &str, char_offset: usize) -> usize {
+    text.char_indices()
+        .nth(char_offset)
+        .map_or(text.len(), |(offset, _)| offset)
+}
+
+/// Char offset of a byte offset in the text
+/// Offsets inside a char are moved back to the start of the char
+pub fn offset_to_char(text: &str, offset: usize) -> usize {
+    text[..floor_boundary(text, offset)].chars().count()
+}
+
+/// Position of a char offset in the text, see [offset_to_position]
+pub fn char_to_position(
+    text: &str,
+    char_offset: usize,
+    encoding: &PositionEncodingKind,
+) -> Position {
+    offset_to_position(text, char_to_offset(text, char_offset), encoding)
+}
+
+/// Char offset of a position in the text, see [position_to_offset]
+pub fn position_to_char(text: &str, position: Position, encoding: &PositionEncodingKind) -> usize {
+    offset_to_char(text, position_to_offset(text, position, encoding))
+}
+
+/// Convert a position from one encoding to another
+///
+/// * `text`: Content of the document
+/// * `position`: Position, in code units of `from`
+/// * `from`: Encoding of the position
+/// * `to`: Encoding of the result
+pub fn convert_position(
+    text: &str,
+    position: Position,
+    from: &PositionEncodingKind,
+    to: &PositionEncodingKind,
+) -> Position {
+    if from == to {
+        return position;
+    }
+
+    offset_to_position(text, position_to_offset(text, position, from), to)
+}
+
+#[cfg(test)]
+mod tests {
+    use lsp_types::{Position, PositionEncodingKind};
+
+    use super::{convert_position, offset_to_position, position_to_offset};
+
+    const UTF8: PositionEncodingKind = PositionEncodingKind::UTF8;
+    const UTF16: PositionEncodingKind = PositionEncodingKind::UTF16;
+    const UTF32: PositionEncodingKind = PositionEncodingKind::UTF32;
+
+    #[test]
+    fn surrogate_pairs() {
+        // 😀 is 4 bytes, 2 UTF-16 code units and 1 char
+        let text = "a😀b";
+        assert_eq!(position_to_offset(text, Position::new(0, 3), &UTF16), 5);
+        assert_eq!(position_to_offset(text, Position::new(0, 5), &UTF8), 5);
+        assert_eq!(position_to_offset(text, Position::new(0, 2), &UTF32), 5);
+
+        assert_eq!(offset_to_position(text, 5, &UTF16), Position::new(0, 3));
+        assert_eq!(offset_to_position(text, 5, &UTF8), Position::new(0, 5));
+        assert_eq!(offset_to_position(text, 5, &UTF32), Position::new(0, 2));
+
+        // Inside the char, moved back to its start
+        assert_eq!(offset_to_position(text, 3, &UTF16), Position::new(0, 1));
+    }
+
+    #[test]
+    fn cjk() {
+        // Every char is 3 bytes, 1 UTF-16 code unit and 1 char
+        let text = "日本語\n語";
+        assert_eq!(position_to_offset(text, Position::new(0, 2), &UTF16), 6);
+        assert_eq!(position_to_offset(text, Position::new(0, 6), &UTF8), 6);
+        assert_eq!(position_to_offset(text, Position::new(1, 1), &UTF32), 13);
+        assert_eq!(offset_to_position(text, 13, &UTF16), Position::new(1, 1));
+
+        let position = convert_position(text, Position::new(0, 2), &UTF16, &UTF8);
+        assert_eq!(position, Position::new(0, 6));
+    }
+
+    #[test]
+    fn clamped_past_the_end() {
+        let text = "ab\ncd";
+        assert_eq!(position_to_offset(text, Position::new(0, 10), &UTF16), 2);
+        assert_eq!(position_to_offset(text, Position::new(1, 10), &UTF16), 5);
+        assert_eq!(position_to_offset(text, Position::new(5, 0), &UTF16), 5);
+        assert_eq!(offset_to_position(text, 100, &UTF16), Position::new(1, 2));
+    }
+
+    #[test]
+    fn line_breaks() {
+        for text in ["ab\ncd", "ab\r\ncd", "ab\rcd"] {
+            let second = text.len() - 2;
+            assert_eq!(position_to_offset(text, Position::new(0, 10), &UTF16), 2);
+            assert_eq!(
+                position_to_offset(text, Position::new(1, 0), &UTF16),
+                second
+            );
+            assert_eq!(
+                offset_to_position(text, second, &UTF16),
+                Position::new(1, 0)
+            );
+            assert_eq!(
+                offset_to_position(text, text.len(), &UTF16),
+                Position::new(1, 2)
+            );
+        }
+
+        // Between `\r` and `\n`, the end of the line
+        assert_eq!(
+            offset_to_position("ab\r\ncd", 3, &UTF16),
+            Position::new(0, 2)
+        );
+
+        // Every kind of line break counts
+        let text = "a\rb\r\nc\nd";
+        assert_eq!(position_to_offset(text, Position::new(3, 0), &UTF16), 7);
+        assert_eq!(offset_to_position(text, 7, &UTF16), Position::new(3, 0));
+
+        // Empty lines
+        assert_eq!(
+            position_to_offset("\r\r\n\n", Position::new(2, 0), &UTF16),
+            3
+        );
+        assert_eq!(
+            offset_to_position("\r\r\n\n", 4, &UTF16),
+            Position::new(3, 0)
+        );
+    }
+}
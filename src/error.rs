@@ -0,0 +1,135 @@
+use std::{fmt, process::ExitStatus, time::Duration};
+
+use lsp_types::Uri;
+
+use crate::LSPError;
+
+/// Error returned when talking to the language server
+#[derive(Debug)]
+pub enum Error {
+    /// The server responded with an error, see [LSPError]
+    Response(LSPError),
+    /// No response arrived before the timeout
+    ///
+    /// * `method`: Method of the request
+    /// * `timeout`: The timeout that was applied
+    Timeout {
+        method: &'static str,
+        timeout: Duration,
+    },
+    /// The request was cancelled. Holds the error of the server if it answered with
+    /// `RequestCancelled` (-32800), `None` if the request was cancelled on our side
+    Cancelled(Option<LSPError>),
+    /// Failed to serialize the params or to deserialize the result
+    Serialization(serde_json::Error),
+    /// The initialize handshake isn't done yet, only lifecycle messages can be sent
+    ServerNotInitialized,
+    /// The initialize handshake is already done, or in progress
+    AlreadyInitialized,
+    /// The server is shutting down, only `exit` can be sent
+    ShuttingDown,
+    /// The channel to the server is closed, nothing can be written to it anymore
+    TransportClosed,
+    /// The server process exited
+    ///
+    /// * `status`: Exit status of the process, if known
+    /// * `stderr`: Last lines the server wrote to stderr
+    ServerExited {
+        status: Option<ExitStatus>,
+        stderr: String,
+    },
+    /// The document isn't open, see [crate::document::DocumentStore::open]
+    DocumentNotOpen(Uri),
+    /// The document is already open, it must be closed before being opened again
+    DocumentAlreadyOpen(Uri),
+    /// The server didn't declare support for the request, see
+    /// [crate::process::LanguageServer::set_capability_check]
+    ///
+    /// * `method`: Method of the request
+    Unsupported { method: &'static str },
+}
+
+impl Error {
+    /// The error code of the response, if the server responded with an error
+    pub fn code(&self) -> Option<i32> {
+        match self {
+            Error::Response(error) => Some(error.code),
+            Error::Cancelled(error) => error.as_ref().map(|error| error.code),
+            _ => None,
+        }
+    }
+
+    /// Whether the server dropped the request because the document changed, `ContentModified` (-32801)
+    /// The request can be retried on the new content
+    pub fn is_content_modified(&self) -> bool {
+        self.code() == Some(lsp_types::error_codes::CONTENT_MODIFIED as i32)
+    }
+
+    /// Whether the server cancelled the request itself, `ServerCancelled` (-32802)
+    pub fn is_server_cancelled(&self) -> bool {
+        self.code() == Some(lsp_types::error_codes::SERVER_CANCELLED as i32)
+    }
+
+    /// Whether the server doesn't know the method, `MethodNotFound` (-32601)
+    pub fn is_method_not_found(&self) -> bool {
+        self.code() == Some(crate::METHOD_NOT_FOUND)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Response(error) => {
+                write!(
+                    f,
+                    "LSP responded with error {}: {}",
+                    error.code, error.message
+                )
+            }
+            Error::Timeout { method, timeout } => {
+                write!(f, "Lsp Request {} time out after {:?}", method, timeout)
+            }
+            Error::Cancelled(Some(error)) => {
+                write!(f, "Request cancelled by the server: {}", error.message)
+            }
+            Error::Cancelled(None) => write!(f, "Request cancelled"),
+            Error::Serialization(error) => write!(f, "Failed to serialize LSP message: {}", error),
+            Error::ServerNotInitialized => write!(f, "Server is not initialized"),
+            Error::AlreadyInitialized => write!(f, "Server is already initialized"),
+            Error::ShuttingDown => write!(f, "Server is shutting down"),
+            Error::TransportClosed => write!(f, "Connection to the server is closed"),
+            Error::ServerExited { status, stderr } => {
+                match status {
+                    Some(status) => write!(f, "Server exited with {}", status)?,
+                    None => write!(f, "Server exited")?,
+                }
+                if !stderr.is_empty() {
+                    write!(f, ", stderr:\n{}", stderr)?;
+                }
+                Ok(())
+            }
+            Error::DocumentNotOpen(uri) => write!(f, "Document {} is not open", uri.as_str()),
+            Error::DocumentAlreadyOpen(uri) => {
+                write!(f, "Document {} is already open", uri.as_str())
+            }
+            Error::Unsupported { method } => {
+                write!(f, "Server does not support {}", method)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Serialization(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(error: serde_json::Error) -> Self {
+        Error::Serialization(error)
+    }
+}
@@ -1,38 +1,83 @@
 use crate::IOKind;
 use std::io::Write;
 use std::path::Path;
-use std::process::Stdio;
-use std::{collections::HashMap, path::PathBuf, sync::Arc};
+use std::process::ExitStatus;
+use std::time::Duration;
+use std::{
+    collections::{HashMap, VecDeque},
+    path::PathBuf,
+    sync::Arc,
+};
 
-use anyhow::{anyhow, Context};
+use anyhow::anyhow;
+use lsp_types::notification::{self, Notification};
+use lsp_types::ProgressToken;
 use parking_lot::Mutex;
+use serde::Deserialize;
 use serde_json::Value;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::process::{self, ChildStderr};
 use tokio::{
     io::{AsyncBufReadExt, BufReader, BufWriter},
-    process::{Child, ChildStdin, ChildStdout},
-    sync::mpsc::{UnboundedReceiver, UnboundedSender},
-    task::JoinHandle,
+    process::{Child, ChildStderr},
+    select,
+    sync::{
+        mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+        oneshot, watch,
+    },
+    task::{AbortHandle, JoinHandle},
 };
 
-use crate::process::LanguageServerBinary;
+use crate::process::ServerState;
+use crate::transport::{Connection, Reader, Writer};
 use crate::{
-    utils, AnyNotification, AnyResponse, LSPError, RequestId, CONTENT_LEN_HEADER, HEADER_DELIMITER,
+    utils, AnyNotification, AnyResponse, Error, RequestId, CONTENT_LEN_HEADER, HEADER_DELIMITER,
+    LSP_SHUTDOWN_TIMEOUT,
 };
 
 // Handler function of io tasks
 pub(crate) type IoHandler = Box<dyn Send + FnMut(IOKind, &str)>;
 
 // Handler function of request tasks
-// Return response as string or LSP error
-pub(crate) type ResponseHandler = Box<dyn Send + FnOnce(Result<String, LSPError>)>;
+// Return response as string or the error of the request
+pub(crate) type ResponseHandler = Box<dyn Send + FnOnce(Result<String, Error>)>;
+
+// Number of stderr lines kept to report why the server exited
+const STDERR_TAIL_LINES: usize = 20;
+
+// Time given to stderr to be drained once the process exited
+const STDERR_DRAIN_TIMEOUT: Duration = Duration::from_millis(100);
+
+// Call every pending response handler with an error, no handler can be registered afterward
+pub(crate) fn fail_pending(
+    response_handlers: &Mutex<Option<HashMap<RequestId, ResponseHandler>>>,
+    error: impl Fn() -> Error,
+) {
+    let handlers = response_handlers.lock().take();
+    for (_, handler) in handlers.into_iter().flatten() {
+        handler(Err(error()));
+    }
+}
+
+// Last lines of stderr, joined
+pub(crate) fn join_tail(tail: &Mutex<VecDeque<String>>) -> String {
+    tail.lock().iter().map(String::as_str).collect()
+}
+
+// Handler function of `$/progress` for the tokens we created, called with the `value` before any
+// notification handler, in order with the responses. Returning the value passes the notification
+// on to the notification handlers
+pub(crate) type ProgressHandler = Box<dyn Send + FnMut(Value) -> Option<Value>>;
 
 // Handler function of notification tasks
 pub(crate) type NotificationHandler = Box<dyn Send + FnMut(Option<RequestId>, Value)>;
 
+// Registered handlers per method, in registration order, keyed by their subscription id
+// Each handler has its own lock so it can be called without holding the map
+pub(crate) type NotificationHandlers =
+    HashMap<&'static str, Vec<(i32, Arc<Mutex<NotificationHandler>>)>>;
+
 pub async fn read_header(
-    reader: &mut BufReader<ChildStdout>,
+    reader: &mut BufReader<Reader>,
     buffer: &mut Vec<u8>,
 ) -> anyhow::Result<()> {
     loop {
@@ -48,11 +93,124 @@ pub async fn read_header(
     }
 }
 
+/// Handle to the server process, the process itself is owned by the process task
+///
+/// * `pid`: Id of the process, `None` if it had already exited when spawned
+/// * `kill_tx`: Ask the process task to kill the process
+/// * `exit_rx`: `None` while the server is running, then the exit status of the process, `None`
+///   for a connection without process
+#[derive(Clone)]
+pub(crate) struct ProcessHandle {
+    pid: Option<u32>,
+    kill_tx: UnboundedSender<()>,
+    exit_rx: watch::Receiver<Option<Option<ExitStatus>>>,
+}
+
+impl ProcessHandle {
+    pub(crate) fn exit_status(&self) -> Option<Option<ExitStatus>> {
+        *self.exit_rx.borrow()
+    }
+
+    /// Ask the process to terminate, SIGTERM on unix. Other platforms can't be asked nicely, the
+    /// process is killed
+    pub(crate) fn terminate(&self) {
+        #[cfg(unix)]
+        if let Some(pid) = self.pid.and_then(|pid| i32::try_from(pid).ok()) {
+            // SAFETY: kill only sends a signal to the process, it doesn't touch our memory
+            unsafe { libc::kill(pid, libc::SIGTERM) };
+            return;
+        }
+
+        self.kill();
+    }
+
+    pub(crate) fn kill(&self) {
+        self.kill_tx.send(()).ok();
+    }
+
+    /// Wait for the process to exit, at most `timeout` if any. `None` if it is still running
+    pub(crate) async fn wait(&self, timeout: Option<Duration>) -> Option<Option<ExitStatus>> {
+        let mut exit_rx = self.exit_rx.clone();
+        let exited = async move {
+            exit_rx
+                .wait_for(Option::is_some)
+                .await
+                .ok()
+                .and_then(|status| *status)
+        };
+
+        match timeout {
+            Some(timeout) => tokio::time::timeout(timeout, exited).await.ok().flatten(),
+            None => exited.await,
+        }
+    }
+
+    /// Wait `grace` for the process to exit by itself, then terminate it and wait `grace` again
+    /// before killing it
+    pub(crate) async fn escalate(&self, grace: Duration) -> Option<Option<ExitStatus>> {
+        if let Some(status) = self.wait(Some(grace)).await {
+            return Some(status);
+        }
+
+        log::warn!("LSP did not exit after {:?}, terminating it", grace);
+        self.terminate();
+        if let Some(status) = self.wait(Some(grace)).await {
+            return Some(status);
+        }
+
+        log::warn!("LSP did not terminate after {:?}, killing it", grace);
+        self.kill();
+        self.wait(None).await
+    }
+}
+
+// Report the end of the server to the pending requests and to the lifecycle state
+struct Exit {
+    exit_tx: watch::Sender<Option<Option<ExitStatus>>>,
+    state: watch::Sender<ServerState>,
+    response_handlers: Arc<Mutex<Option<HashMap<RequestId, ResponseHandler>>>>,
+    stderr_tail: Arc<Mutex<VecDeque<String>>>,
+    stderr_done: Option<oneshot::Receiver<()>>,
+}
+
+impl Exit {
+    // `status` is `None` when there was no process, only a connection
+    async fn exited(self, status: Option<ExitStatus>) {
+        // Give stderr a chance to be drained, it usually tell why the server exited
+        if let Some(stderr_done) = self.stderr_done {
+            tokio::time::timeout(STDERR_DRAIN_TIMEOUT, stderr_done)
+                .await
+                .ok();
+        }
+
+        self.state.send_modify(|state| {
+            *state = match state {
+                ServerState::ShuttingDown => ServerState::Exited(status),
+                _ => ServerState::Crashed(status),
+            }
+        });
+
+        match status {
+            Some(status) if status.success() => log::info!("LSP exited with {}", status),
+            Some(status) => log::error!("LSP exited with {}", status),
+            None => log::info!("LSP connection closed"),
+        }
+
+        let stderr = join_tail(&self.stderr_tail);
+        fail_pending(&self.response_handlers, || Error::ServerExited {
+            status,
+            stderr: stderr.clone(),
+        });
+
+        self.exit_tx.send_replace(Some(status));
+    }
+}
+
 pub(crate) struct IO {
     stderr_task: JoinHandle<anyhow::Result<()>>,
     stdin_task: JoinHandle<anyhow::Result<()>>,
     stdout_task: JoinHandle<anyhow::Result<()>>,
-    process: Arc<Mutex<Child>>,
+    process: ProcessHandle,
     working_dir: PathBuf,
     root_path: PathBuf,
     name: Arc<str>,
@@ -63,94 +221,135 @@ impl IO {
     #[allow(clippy::too_many_arguments)]
     pub(crate) fn new(
         id: i32,
-        binary: LanguageServerBinary,
+        connection: Connection,
         response_handlers: Arc<Mutex<Option<HashMap<RequestId, ResponseHandler>>>>,
+        progress_handlers: Arc<Mutex<HashMap<ProgressToken, ProgressHandler>>>,
         io_handlers: Arc<Mutex<HashMap<i32, IoHandler>>>,
         request_rx: UnboundedReceiver<String>,
         notification_tx: UnboundedSender<AnyNotification>,
         output_done: UnboundedSender<String>,
         root_path: &Path,
         capture: Arc<Mutex<Option<String>>>,
+        state: watch::Sender<ServerState>,
+        stderr_tail: Arc<Mutex<VecDeque<String>>>,
     ) -> anyhow::Result<Self> {
-        let working_dir = if root_path.is_dir() {
-            root_path
-        } else {
-            root_path.parent().unwrap_or_else(|| Path::new("/"))
+        let Connection {
+            reader,
+            writer,
+            mut process,
+            name,
+        } = connection;
+
+        let (kill_tx, kill_rx) = unbounded_channel();
+        let (exit_tx, exit_rx) = watch::channel(None);
+        let (output_closed_tx, output_closed_rx) = oneshot::channel();
+        let (stderr_done_tx, stderr_done_rx) = oneshot::channel();
+
+        let handle = ProcessHandle {
+            pid: process.as_ref().and_then(Child::id),
+            kill_tx,
+            exit_rx,
         };
 
-        log::info!(
-            "Starting LSP. Path: {:?}, working directory: {:?}, args: {:?}",
-            binary.path.to_str(),
-            working_dir.to_str(),
-            &binary.args
-        );
+        // Only spawned servers have a stderr
+        let stderr = process.as_mut().and_then(|process| process.stderr.take());
+        let stderr_done = stderr.is_some().then_some(stderr_done_rx);
+        let stderr_task = match stderr {
+            Some(stderr) => Self::stderr_task(
+                stderr,
+                io_handlers.clone(),
+                capture,
+                stderr_tail.clone(),
+                stderr_done_tx,
+            ),
+            None => tokio::spawn(async { Ok(()) }),
+        };
 
-        let mut command = process::Command::new(&binary.path);
-        command
-            .current_dir(working_dir)
-            .args(&binary.args)
-            .envs(binary.envs.unwrap_or_default())
-            .stdout(Stdio::piped())
-            .stdin(Stdio::piped())
-            .stderr(Stdio::piped())
-            .kill_on_drop(true);
-
-        let mut server = command
-            .spawn()
-            .with_context(|| "failed to spawn lsp server".to_string())?;
-
-        let stdin = server.stdin.take().unwrap();
-        let stdout = server.stdout.take().unwrap();
-        let stderr = server.stderr.take().unwrap();
-
-        let stderr_task = Self::stderr_task(stderr, io_handlers.clone(), capture);
         let stdout_task = Self::stdout_task(
-            stdout,
+            reader,
             io_handlers.clone(),
             response_handlers.clone(),
+            progress_handlers,
             notification_tx,
+            handle.clone(),
+            output_closed_tx,
         );
 
-        let stdin_task = Self::stdin_task(
-            stdin,
-            response_handlers,
-            io_handlers,
-            request_rx,
-            output_done,
+        let stdin_task = Self::stdin_task(writer, io_handlers, request_rx, output_done);
+
+        Self::process_task(
+            process,
+            kill_rx,
+            output_closed_rx,
+            [stdin_task.abort_handle(), stdout_task.abort_handle()],
+            Exit {
+                exit_tx,
+                state,
+                response_handlers,
+                stderr_tail,
+                stderr_done,
+            },
         );
 
-        let name = match binary.path.file_name() {
-            Some(name) => name.to_string_lossy().into(),
-            None => Arc::default(),
-        };
-
         Ok(Self {
             stderr_task,
             stdin_task,
             stdout_task,
-            process: Arc::new(Mutex::new(server)),
-            working_dir: working_dir.to_path_buf(),
+            process: handle,
+            working_dir: utils::working_dir(root_path).to_path_buf(),
             root_path: root_path.to_path_buf(),
             name,
             id,
         })
     }
 
+    // Own the process until it exits, killing it on demand or once every handle is gone.
+    // Without process, wait for the server to close its output, killing means closing the
+    // connection
+    fn process_task(
+        process: Option<Child>,
+        mut kill_rx: UnboundedReceiver<()>,
+        output_closed: oneshot::Receiver<()>,
+        connection: [AbortHandle; 2],
+        exit: Exit,
+    ) -> JoinHandle<anyhow::Result<()>> {
+        tokio::spawn(async move {
+            let status = match process {
+                Some(mut process) => {
+                    let mut killed = false;
+                    loop {
+                        select! {
+                            status = process.wait() => break Some(status?),
+                            _ = kill_rx.recv(), if !killed => {
+                                killed = true;
+                                process.start_kill()?;
+                            }
+                        }
+                    }
+                }
+                None => {
+                    select! {
+                        _ = output_closed => {}
+                        _ = kill_rx.recv() => {}
+                    }
+                    connection.iter().for_each(AbortHandle::abort);
+                    None
+                }
+            };
+
+            exit.exited(status).await;
+            Ok(())
+        })
+    }
+
     pub fn stdin_task(
-        stdin: ChildStdin,
-        response_handlers: Arc<Mutex<Option<HashMap<RequestId, ResponseHandler>>>>,
+        stdin: Writer,
         io_handlers: Arc<Mutex<HashMap<i32, IoHandler>>>,
         mut request_rx: UnboundedReceiver<String>,
         output_done: UnboundedSender<String>,
     ) -> JoinHandle<anyhow::Result<()>> {
         tokio::spawn(async move {
             let mut buff_writer = BufWriter::new(stdin);
-            let _clear_response_handler = utils::defer({
-                let response_handlers = response_handlers.clone();
-                move || {
-                    response_handlers.lock().take();
-                }
-            });
 
             let mut content_len_buffer: Vec<u8> = Vec::new();
 
@@ -181,81 +380,134 @@ impl IO {
     }
 
     pub fn stdout_task(
-        stdout: ChildStdout,
+        stdout: Reader,
         io_handlers: Arc<Mutex<HashMap<i32, IoHandler>>>,
         response_handlers: Arc<Mutex<Option<HashMap<RequestId, ResponseHandler>>>>,
+        progress_handlers: Arc<Mutex<HashMap<ProgressToken, ProgressHandler>>>,
         notification_tx: UnboundedSender<AnyNotification>,
+        process: ProcessHandle,
+        output_closed: oneshot::Sender<()>,
     ) -> JoinHandle<anyhow::Result<()>> {
         tokio::spawn(async move {
-            let mut buff_reader = BufReader::new(stdout);
-            let mut buffer: Vec<u8> = Vec::new();
-
-            loop {
-                buffer.clear();
-                read_header(&mut buff_reader, &mut buffer).await?;
-
-                let header = std::str::from_utf8(&buffer)?;
-
-                let content_len = header
-                    .split('\n')
-                    .find(|line| line.starts_with(CONTENT_LEN_HEADER))
-                    .and_then(|line| line.strip_prefix(CONTENT_LEN_HEADER))
-                    .ok_or_else(|| anyhow!("Invalid LSP header"))?
-                    .trim_end()
-                    .parse()?;
+            let output = Self::read_output(
+                stdout,
+                io_handlers,
+                response_handlers.clone(),
+                progress_handlers,
+                notification_tx,
+            )
+            .await;
+            drop(output_closed);
+
+            // The output is closed, the process is most likely exiting. Let it report its exit
+            // status to the pending requests, or fail them ourselves if it takes too long
+            process.wait(Some(LSP_SHUTDOWN_TIMEOUT)).await;
+            fail_pending(&response_handlers, || Error::TransportClosed);
+
+            output
+        })
+    }
 
-                buffer.resize(content_len, 0);
-                buff_reader.read_exact(&mut buffer).await?;
+    async fn read_output(
+        stdout: Reader,
+        io_handlers: Arc<Mutex<HashMap<i32, IoHandler>>>,
+        response_handlers: Arc<Mutex<Option<HashMap<RequestId, ResponseHandler>>>>,
+        progress_handlers: Arc<Mutex<HashMap<ProgressToken, ProgressHandler>>>,
+        notification_tx: UnboundedSender<AnyNotification>,
+    ) -> anyhow::Result<()> {
+        let mut buff_reader = BufReader::new(stdout);
+        let mut buffer: Vec<u8> = Vec::new();
+
+        loop {
+            buffer.clear();
+            read_header(&mut buff_reader, &mut buffer).await?;
+
+            let header = std::str::from_utf8(&buffer)?;
+
+            let content_len = header
+                .split('\n')
+                .find(|line| line.starts_with(CONTENT_LEN_HEADER))
+                .and_then(|line| line.strip_prefix(CONTENT_LEN_HEADER))
+                .ok_or_else(|| anyhow!("Invalid LSP header"))?
+                .trim_end()
+                .parse()?;
+
+            buffer.resize(content_len, 0);
+            buff_reader.read_exact(&mut buffer).await?;
+
+            // Check if message is valid utf8
+            if let Ok(message) = std::str::from_utf8(&buffer) {
+                log::trace!("LSP send : {}", message);
+                // We got response, execute the io handler
+                for handler in io_handlers.lock().values_mut() {
+                    handler(IOKind::Out, message);
+                }
+            }
 
-                // Check if message is valid utf8
-                if let Ok(message) = std::str::from_utf8(&buffer) {
-                    log::trace!("LSP send : {}", message);
-                    // We got response, execute the io handler
-                    for handler in io_handlers.lock().values_mut() {
-                        handler(IOKind::Out, message);
+            if let Ok(mut message) = serde_json::from_slice::<AnyNotification>(&buffer) {
+                // Progress of our requests is handled here, so it is delivered before the
+                // response of the request
+                if message.method == notification::Progress::METHOD {
+                    if let Some(params) = message.params.as_mut() {
+                        let token = params
+                            .get("token")
+                            .and_then(|token| ProgressToken::deserialize(token).ok());
+
+                        let mut progress_handlers = progress_handlers.lock();
+                        if let Some(handler) =
+                            token.and_then(|token| progress_handlers.get_mut(&token))
+                        {
+                            match handler(params["value"].take()) {
+                                Some(value) => params["value"] = value,
+                                None => continue,
+                            }
+                        }
                     }
                 }
 
-                if let Ok(message) = serde_json::from_slice::<AnyNotification>(&buffer) {
-                    notification_tx.send(message)?;
-                } else if let Ok(AnyResponse {
-                    id, result, error, ..
-                }) = serde_json::from_slice(&buffer)
+                notification_tx.send(message)?;
+            } else if let Ok(AnyResponse {
+                id, result, error, ..
+            }) = serde_json::from_slice(&buffer)
+            {
+                let mut response_handlers = response_handlers.lock();
+
+                // Get the available handler method and execute it
+                if let Some(handler) = response_handlers
+                    .as_mut()
+                    .and_then(|handlers| handlers.remove(&id))
                 {
-                    let mut response_handlers = response_handlers.lock();
-
-                    // Get the available handler method and execute it
-                    if let Some(handler) = response_handlers
-                        .as_mut()
-                        .and_then(|handlers| handlers.remove(&id))
-                    {
-                        drop(response_handlers);
-
-                        if let Some(error) = error {
-                            handler(Err(error))
-                        } else if let Some(result) = result {
-                            handler(Ok(result.get().into()))
-                        } else {
-                            log::trace!("No result or error");
-                            handler(Ok("null".into()))
-                        }
+                    drop(response_handlers);
+
+                    if let Some(error) = error {
+                        handler(Err(Error::Response(error)))
+                    } else if let Some(result) = result {
+                        handler(Ok(result.get().into()))
+                    } else {
+                        log::trace!("No result or error");
+                        handler(Ok("null".into()))
                     }
-                } else {
-                    log::warn!(
-                        "Failed to deserialize LSP message: {}",
-                        std::str::from_utf8(&buffer)?
-                    );
                 }
+            } else {
+                log::warn!(
+                    "Failed to deserialize LSP message: {}",
+                    std::str::from_utf8(&buffer)?
+                );
             }
-        })
+        }
     }
 
     pub fn stderr_task(
         stderr: ChildStderr,
         io_handlers: Arc<Mutex<HashMap<i32, IoHandler>>>,
         capture: Arc<Mutex<Option<String>>>,
+        tail: Arc<Mutex<VecDeque<String>>>,
+        done: oneshot::Sender<()>,
     ) -> JoinHandle<anyhow::Result<()>> {
         tokio::spawn(async move {
+            let _done = utils::defer(move || {
+                done.send(()).ok();
+            });
             let mut buf_reader = BufReader::new(stderr);
             let mut buffer: Vec<u8> = Vec::new();
 
@@ -276,6 +528,12 @@ impl IO {
                     if let Some(stderr) = capture.lock().as_mut() {
                         stderr.push_str(message)
                     }
+
+                    let mut tail = tail.lock();
+                    if tail.len() == STDERR_TAIL_LINES {
+                        tail.pop_front();
+                    }
+                    tail.push_back(message.to_string());
                 }
                 tokio::task::yield_now().await;
             }
@@ -298,12 +556,16 @@ impl IO {
         self.id
     }
 
+    pub(crate) fn process(&self) -> &ProcessHandle {
+        &self.process
+    }
+
     pub(crate) fn kill(&mut self) -> anyhow::Result<()> {
         self.stdin_task.abort();
         self.stdout_task.abort();
         self.stderr_task.abort();
 
-        self.process.lock().start_kill()?;
+        self.process.kill();
         Ok(())
     }
 }
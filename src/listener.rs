@@ -1,299 +1,793 @@
-use anyhow::anyhow;
-use anyhow::Context;
 use lsp_types::error_codes;
-use serde::Serialize;
+use serde::{de::DeserializeOwned, Serialize};
 use serde_json::Value;
 use std::future::Future;
-use std::future::IntoFuture;
+use std::time::Duration;
 use std::{
-    collections::HashMap,
-    sync::{atomic::AtomicI32, Arc},
+    collections::{HashMap, VecDeque},
+    sync::{
+        atomic::{AtomicI32, Ordering},
+        Arc,
+    },
 };
 use tokio::task::JoinHandle;
 
-use lsp_types::{notification, request};
-use parking_lot::Mutex;
+use lsp_types::notification::Notification;
+use lsp_types::request::Request;
+use lsp_types::{notification, request, CancelParams, NumberOrString, ProgressToken};
+use parking_lot::{Mutex, RwLock};
 use tokio::{
     select,
     sync::{
         mpsc::{UnboundedReceiver, UnboundedSender},
-        oneshot,
+        oneshot, watch,
     },
 };
 
+use crate::process::ServerState;
+use crate::progress::{self, ProgressEvent};
+use crate::stream::{self, LagPolicy, NotificationStream, PartialResultStream};
 use crate::utils;
 use crate::utils::Subscription;
 use crate::AnyResponse;
+use crate::Error;
 use crate::IOKind;
 use crate::LSPError;
 use crate::LSPResponse;
 use crate::LSP_REQUEST_TIMEOUT;
 use crate::{
-    io::{IoHandler, NotificationHandler, ResponseHandler},
-    AnyNotification, LSPNotification, LSPRequest, RequestId, JSON_RPC_VERSION,
+    io::{
+        self, IoHandler, NotificationHandler, NotificationHandlers, ProgressHandler,
+        ResponseHandler,
+    },
+    AnyNotification, LSPNotification, LSPRequest, RequestId, JSON_RPC_VERSION, METHOD_NOT_FOUND,
 };
 
+/// Handle to a request that had been sent to the server
+///
+/// * `id`: Id of the request
+/// * `request_tx`: Sender used to write `$/cancelRequest` to the server
+/// * `response_handlers`: Pending response handlers, the request's handler will be removed on cancel
+#[derive(Clone)]
+pub struct CancelHandle {
+    id: RequestId,
+    request_tx: UnboundedSender<String>,
+    response_handlers: Arc<Mutex<Option<HashMap<RequestId, ResponseHandler>>>>,
+}
+
+impl CancelHandle {
+    /// Id of the request
+    pub fn id(&self) -> &RequestId {
+        &self.id
+    }
+
+    /// Cancel the request, removing its response handler and sending `$/cancelRequest` to the
+    /// server. Does nothing if the response had already arrived
+    pub fn cancel(&self) {
+        if !self.remove_handler() {
+            return;
+        }
+
+        let id = match &self.id {
+            RequestId::Int(id) => NumberOrString::Number(*id),
+            RequestId::Str(id) => NumberOrString::String(id.clone()),
+        };
+
+        if let Ok(message) = serde_json::to_string(&LSPNotification {
+            jsonrpc: JSON_RPC_VERSION,
+            method: notification::Cancel::METHOD,
+            params: CancelParams { id },
+        }) {
+            self.request_tx.send(message).ok();
+        }
+    }
+
+    // Remove the pending response handler, return false if there was none
+    fn remove_handler(&self) -> bool {
+        self.response_handlers
+            .lock()
+            .as_mut()
+            .and_then(|handlers| handlers.remove(&self.id))
+            .is_some()
+    }
+}
+
+/// Write notifications to the server without borrowing the [Listener]
+/// Useful for background tasks, the initialize check is only applied by [Notifier::notify_checked]
+#[derive(Clone)]
+pub(crate) struct Notifier {
+    request_tx: UnboundedSender<String>,
+    state: watch::Sender<ServerState>,
+    stderr_tail: Arc<Mutex<VecDeque<String>>>,
+}
+
+impl Notifier {
+    pub(crate) fn notify<T: notification::Notification>(
+        &self,
+        params: T::Params,
+    ) -> Result<(), Error> {
+        let message = serde_json::to_string(&LSPNotification {
+            jsonrpc: JSON_RPC_VERSION,
+            method: T::METHOD,
+            params,
+        })?;
+
+        self.request_tx
+            .send(message)
+            .map_err(|_| Error::TransportClosed)
+    }
+
+    // Whether anything but lifecycle messages can be sent
+    pub(crate) fn check_state(&self) -> Result<(), Error> {
+        check_state(&self.state, &self.stderr_tail, false)
+    }
+
+    // Notify the server once the handshake is done, for anything but lifecycle messages
+    pub(crate) fn notify_checked<T: notification::Notification>(
+        &self,
+        params: T::Params,
+    ) -> Result<(), Error> {
+        self.check_state()?;
+        self.notify::<T>(params)
+    }
+}
+
+/// Whether the server supports a request, from its method and params
+/// The params are only serialized if the check calls for them
+pub(crate) type CapabilityCheck =
+    Box<dyn Send + Sync + Fn(&str, &dyn Fn() -> Option<Value>) -> bool>;
+
+/// Timeouts applied to outgoing requests
+///
+/// * `default`: Timeout for every method without an override, `None` means no timeout
+/// * `methods`: Per method overrides, keyed by the request method
+/// * `cancel_on_timeout`: Send `$/cancelRequest` to the server when a request time out
+#[derive(Debug, Clone)]
+pub(crate) struct RequestTimeouts {
+    pub(crate) default: Option<Duration>,
+    pub(crate) methods: HashMap<&'static str, Option<Duration>>,
+    pub(crate) cancel_on_timeout: bool,
+}
+
+impl Default for RequestTimeouts {
+    fn default() -> Self {
+        Self {
+            default: Some(LSP_REQUEST_TIMEOUT),
+            methods: HashMap::default(),
+            cancel_on_timeout: true,
+        }
+    }
+}
+
+impl RequestTimeouts {
+    fn get(&self, method: &str) -> Option<Duration> {
+        self.methods.get(method).copied().unwrap_or(self.default)
+    }
+}
+
 pub(crate) struct Listener {
     next_id: AtomicI32,
+    state: watch::Sender<ServerState>,
+    stderr_tail: Arc<Mutex<VecDeque<String>>>,
+    timeouts: RwLock<RequestTimeouts>,
+    capability_check: RwLock<Option<CapabilityCheck>>,
     request_tx: UnboundedSender<String>,
     response_handlers: Arc<Mutex<Option<HashMap<RequestId, ResponseHandler>>>>,
+    progress_handlers: Arc<Mutex<HashMap<ProgressToken, ProgressHandler>>>,
     io_handlers: Arc<Mutex<HashMap<i32, IoHandler>>>,
-    notification_handlers: Arc<Mutex<HashMap<&'static str, NotificationHandler>>>,
+    notification_handlers: Arc<Mutex<NotificationHandlers>>,
+    request_handlers: Arc<Mutex<NotificationHandlers>>,
+    // Requests answered by the crate itself, a default reply takes precedence
+    fallback_handlers: Arc<Mutex<NotificationHandlers>>,
+    default_replies: Arc<Mutex<HashMap<String, Value>>>,
     output_task: JoinHandle<anyhow::Result<()>>,
 }
 
 impl Listener {
+    #[allow(clippy::too_many_arguments)]
     pub(crate) fn new(
         notification_rx: UnboundedReceiver<AnyNotification>,
-        notification_handlers: Arc<Mutex<HashMap<&'static str, NotificationHandler>>>,
+        notification_handlers: Arc<Mutex<NotificationHandlers>>,
         response_handlers: Arc<Mutex<Option<HashMap<RequestId, ResponseHandler>>>>,
+        progress_handlers: Arc<Mutex<HashMap<ProgressToken, ProgressHandler>>>,
         io_handlers: Arc<Mutex<HashMap<i32, IoHandler>>>,
         request_tx: UnboundedSender<String>,
+        state: watch::Sender<ServerState>,
+        stderr_tail: Arc<Mutex<VecDeque<String>>>,
     ) -> anyhow::Result<Self> {
+        let request_handlers = Arc::new(Mutex::new(NotificationHandlers::default()));
+        let fallback_handlers = Arc::new(Mutex::new(NotificationHandlers::default()));
+        let default_replies = Arc::new(Mutex::new(HashMap::default()));
         let output_task = Self::handle_output(
             notification_handlers.clone(),
-            response_handlers.clone(),
+            request_handlers.clone(),
+            fallback_handlers.clone(),
+            default_replies.clone(),
+            request_tx.clone(),
             notification_rx,
         );
 
         Ok(Self {
             next_id: Default::default(),
+            state,
+            stderr_tail,
+            timeouts: Default::default(),
+            capability_check: Default::default(),
             request_tx,
             response_handlers,
+            progress_handlers,
             io_handlers,
             notification_handlers,
+            request_handlers,
+            fallback_handlers,
+            default_replies,
             output_task,
         })
     }
 
     fn handle_output(
-        notification_handlers: Arc<Mutex<HashMap<&'static str, NotificationHandler>>>,
-        response_handlers: Arc<Mutex<Option<HashMap<RequestId, ResponseHandler>>>>,
+        notification_handlers: Arc<Mutex<NotificationHandlers>>,
+        request_handlers: Arc<Mutex<NotificationHandlers>>,
+        fallback_handlers: Arc<Mutex<NotificationHandlers>>,
+        default_replies: Arc<Mutex<HashMap<String, Value>>>,
+        request_tx: UnboundedSender<String>,
         mut notification_rx: UnboundedReceiver<AnyNotification>,
     ) -> JoinHandle<anyhow::Result<()>> {
         tokio::spawn(async move {
-            let _clear_response_handlers = utils::defer({
-                let response_handlers = response_handlers.clone();
-                move || {
-                    response_handlers.lock().take();
-                }
-            });
-
             while let Some(message) = notification_rx.recv().await {
-                {
-                    let mut notification_handlers = notification_handlers.lock();
-                    if let Some(handler) = notification_handlers.get_mut(message.method.as_str()) {
-                        handler(message.id, message.params.unwrap_or(Value::Null));
-                    } else {
-                        drop(notification_handlers);
+                let params = message.params.unwrap_or(Value::Null);
+
+                match message.id {
+                    // Every subscriber gets the notification
+                    // Handlers are called outside of the map's lock, they may subscribe or
+                    // unsubscribe
+                    None => {
+                        let handlers = notification_handlers
+                            .lock()
+                            .get(message.method.as_str())
+                            .map(|handlers| {
+                                handlers
+                                    .iter()
+                                    .map(|(_, handler)| handler.clone())
+                                    .collect::<Vec<_>>()
+                            })
+                            .unwrap_or_default();
+
+                        for handler in handlers {
+                            (handler.lock())(None, params.clone());
+                        }
+                    }
+                    // Only one answer per request, the last registered handler replies. The
+                    // handlers of the crate only reply without handler nor default reply
+                    Some(id) => {
+                        let last = |handlers: &Mutex<NotificationHandlers>| {
+                            handlers
+                                .lock()
+                                .get(message.method.as_str())
+                                .and_then(|handlers| handlers.last())
+                                .map(|(_, handler)| handler.clone())
+                        };
+                        let handler = last(&request_handlers).or_else(|| {
+                            match default_replies.lock().contains_key(&message.method) {
+                                true => None,
+                                false => last(&fallback_handlers),
+                            }
+                        });
+
+                        if let Some(handler) = handler {
+                            (handler.lock())(Some(id), params);
+                        } else {
+                            // The server is waiting for an answer, never leave it hanging
+                            Self::reply_unhandled(
+                                &default_replies,
+                                &request_tx,
+                                id,
+                                message.method,
+                            );
+                        }
                     }
                 }
 
                 tokio::task::yield_now().await;
             }
 
+            // The server is gone, drop the handlers so the streams they feed end
+            notification_handlers.lock().clear();
+            request_handlers.lock().clear();
+            fallback_handlers.lock().clear();
+
             Ok(())
         })
     }
 
+    // Reply the default reply of the method, or `MethodNotFound` if there is none
+    fn reply_unhandled(
+        default_replies: &Mutex<HashMap<String, Value>>,
+        request_tx: &UnboundedSender<String>,
+        id: RequestId,
+        method: String,
+    ) {
+        let reply = default_replies.lock().get(&method).cloned();
+        let response = match reply {
+            Some(result) => serde_json::to_string(&LSPResponse {
+                jsonrpc: JSON_RPC_VERSION,
+                id,
+                value: crate::LSPResult::Ok(Some(result)),
+            }),
+            None => {
+                log::warn!("Unhandled LSP request: {}", method);
+                serde_json::to_string(&LSPResponse::<()> {
+                    jsonrpc: JSON_RPC_VERSION,
+                    id,
+                    value: crate::LSPResult::Err(Some(LSPError {
+                        message: format!("Unhandled method {}", method),
+                        code: METHOD_NOT_FOUND,
+                        data: None,
+                    })),
+                })
+            }
+        };
+
+        if let Ok(response) = response {
+            request_tx.send(response).ok();
+        }
+    }
+
     pub(crate) async fn request<T: request::Request>(
         &self,
         params: T::Params,
-    ) -> anyhow::Result<T::Result> {
-        let id = self
-            .next_id
-            .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+    ) -> Result<T::Result, Error> {
+        let (_, response) = self.request_cancellable::<T>(params);
+        response.await
+    }
 
-        let message = serde_json::to_string(&LSPRequest {
-            jsonrpc: JSON_RPC_VERSION,
-            id: RequestId::Int(id),
-            method: T::METHOD,
-            params,
-        })
-        .unwrap();
+    pub(crate) fn request_cancellable<T: request::Request>(
+        &self,
+        params: T::Params,
+    ) -> (
+        CancelHandle,
+        impl Future<Output = Result<T::Result, Error>> + Send + 'static,
+    ) {
+        let timeout = self.timeouts.read().get(T::METHOD);
+        self.request_with_timeout::<T>(params, timeout)
+    }
 
-        let (tx, rx) = oneshot::channel();
+    /// Write the request to the server and return a handle to cancel it, along with the
+    /// future resolving the response. Dropping the future before it resolves cancel the request
+    ///
+    /// * `params`: Request params
+    /// * `timeout`: Time to wait for the response, `None` wait forever
+    pub(crate) fn request_with_timeout<T: request::Request>(
+        &self,
+        params: T::Params,
+        timeout: Option<Duration>,
+    ) -> (
+        CancelHandle,
+        impl Future<Output = Result<T::Result, Error>> + Send + 'static,
+    ) {
+        self.send_request::<T::Params, T::Result>(T::METHOD, params, timeout)
+    }
 
-        let handle_response = self
-            .response_handlers
-            .clone()
-            .lock()
-            .as_mut()
-            .ok_or_else(|| anyhow!("Server shutdown"))
-            .map(|handler| {
-                handler.insert(
-                    RequestId::Int(id),
-                    Box::new(move |result| {
-                        tokio::spawn(async move {
-                            let response = match result {
-                                Ok(message) => match serde_json::from_str(&message) {
-                                    Ok(desirialized) => Ok(desirialized),
-                                    Err(error) => {
-                                        log::error!("Failed to deserialize the LSP response: {}. Error: {}", message, error);
-                                        Err(error).context("Failed to deserialize LSP message")
-                                    }
-                                },
-                                Err(e) => Err(anyhow!("{}", e.message)),
-                            };
-                            _ =tx.send(response)
-                        });
-                    }),
-                )
-            });
+    // Send a request and get the response back as a partial result stream
+    // `partialResultToken` is added to the parameters, they must be an object
+    pub(crate) fn request_streaming<T: request::Request>(
+        &self,
+        params: T::Params,
+    ) -> PartialResultStream<T::Result> {
+        let token = self.progress_token("partial-result");
+
+        // Partial results must not be dropped, the buffer is unbounded
+        let (sender, partials) = stream::channel(usize::MAX, LagPolicy::DropNewest);
+        let partials = partials.with_subscription(self.on_progress(
+            token.clone(),
+            Box::new(move |value| {
+                sender.send(value);
+                None
+            }),
+        ));
 
-        let request_out_rx = &self.request_tx.clone();
-        let send = request_out_rx
-            .send(message)
-            .context("Failed to write to LSP stdin");
-        let _ = request_out_rx.downgrade();
+        let params = match with_token(params, "partialResultToken", &token) {
+            Ok(params) => params,
+            Err(error) => return PartialResultStream::new(partials, async move { Err(error) }),
+        };
+
+        let timeout = self.timeouts.read().get(T::METHOD);
+        let (_, response) = self.send_request::<Value, Value>(T::METHOD, params, timeout);
+
+        PartialResultStream::new(partials, response)
+    }
 
-        let timeout_task = tokio::spawn(async move {
-            tokio::time::sleep(LSP_REQUEST_TIMEOUT).await;
+    // Send a request with a fresh `workDoneToken`, and get the progress of the request as a stream
+    // The parameters must be an object. The stream ends once the response arrived
+    pub(crate) fn request_with_progress<T: request::Request>(
+        &self,
+        params: T::Params,
+        capacity: usize,
+        policy: LagPolicy,
+    ) -> (
+        impl Future<Output = Result<T::Result, Error>> + Send + 'static,
+        NotificationStream<ProgressEvent>,
+    ) {
+        let token = self.progress_token("work-done");
+
+        let (sender, events) = stream::channel(capacity, policy);
+        let events = events.with_subscription(self.on_progress(
+            token.clone(),
+            progress::request_handler(token.clone(), sender),
+        ));
+
+        let timeout = self.timeouts.read().get(T::METHOD);
+        let sent = with_token(params, "workDoneToken", &token).map(|params| {
+            self.send_request::<Value, T::Result>(T::METHOD, params, timeout)
+                .1
         });
 
-        let response_handle = tokio::spawn(async move {
-            handle_response.unwrap_or_default();
-            send.unwrap_or_default();
-            match rx.into_future().await {
-                Ok(response) => response,
-                Err(e) => Err(e.into()),
+        let progress_handlers = Arc::downgrade(&self.progress_handlers);
+        let response = async move {
+            let response = sent?.await;
+
+            // The request is done, so is its progress
+            if let Some(progress_handlers) = progress_handlers.upgrade() {
+                progress_handlers.lock().remove(&token);
             }
-        });
 
-        select! {
-            response = response_handle => {
-                match response  {
-                    Ok(res) => res,
-                    Err(e)=> Err(e.into())
-                }
+            response
+        };
+
+        (response, events)
+    }
+
+    // A token unique to this server, for the progress of one of our requests
+    fn progress_token(&self, kind: &str) -> ProgressToken {
+        ProgressToken::String(format!(
+            "chan-rs/{}/{}",
+            kind,
+            self.next_id.fetch_add(1, Ordering::SeqCst)
+        ))
+    }
+
+    fn on_progress(&self, token: ProgressToken, handler: ProgressHandler) -> Subscription {
+        self.progress_handlers.lock().insert(token.clone(), handler);
+
+        Subscription::Progress {
+            token,
+            progress_handlers: Some(Arc::downgrade(&self.progress_handlers)),
+        }
+    }
+
+    fn send_request<P: Serialize, R: DeserializeOwned + Send + 'static>(
+        &self,
+        method: &'static str,
+        params: P,
+        timeout: Option<Duration>,
+    ) -> (
+        CancelHandle,
+        impl Future<Output = Result<R, Error>> + Send + 'static,
+    ) {
+        let cancel_on_timeout = self.timeouts.read().cancel_on_timeout;
+        let id = RequestId::Int(self.next_id.fetch_add(1, Ordering::SeqCst));
+
+        let (tx, rx) = oneshot::channel::<Result<String, Error>>();
+
+        let cancel_handle = CancelHandle {
+            id: id.clone(),
+            request_tx: self.request_tx.clone(),
+            response_handlers: self.response_handlers.clone(),
+        };
+
+        let sent = self
+            .check_state(
+                method == request::Initialize::METHOD || method == request::Shutdown::METHOD,
+            )
+            .and_then(|_| self.check_capability(method, &params))
+            .and_then(|_| {
+                serde_json::to_string(&LSPRequest {
+                    jsonrpc: JSON_RPC_VERSION,
+                    id: id.clone(),
+                    method,
+                    params,
+                })
+                .map_err(Error::from)
+            })
+            .and_then(|message| {
+                self.response_handlers
+                    .lock()
+                    .as_mut()
+                    .ok_or(Error::TransportClosed)?
+                    .insert(
+                        id.clone(),
+                        Box::new(move |result| {
+                            _ = tx.send(result);
+                        }),
+                    );
+
+                self.request_tx.send(message).map_err(|_| {
+                    if let Some(handlers) = self.response_handlers.lock().as_mut() {
+                        handlers.remove(&id);
+                    }
+                    Error::TransportClosed
+                })
+            });
+
+        let response_handlers = self.response_handlers.clone();
+        let cancel = cancel_handle.clone();
+        let cancel_guard = cancel_handle.clone();
+
+        // Cancel the request if this future get dropped before the response arrived, even if it
+        // was never polled
+        let cancel_on_drop = sent
+            .is_ok()
+            .then(|| utils::defer(move || cancel_guard.cancel()));
+
+        let response = async move {
+            sent?;
+
+            let response = match timeout {
+                Some(timeout) => select! {
+                    response = rx => response,
+                    _ = tokio::time::sleep(timeout) => {
+                        if let Some(cancel_on_drop) = cancel_on_drop {
+                            cancel_on_drop.abort();
+                        }
+                        if cancel_on_timeout {
+                            cancel.cancel();
+                        } else {
+                            cancel.remove_handler();
+                        }
+                        return Err(Error::Timeout {
+                            method,
+                            timeout,
+                        });
+                    }
+                },
+                None => rx.await,
+            };
+
+            if let Some(cancel_on_drop) = cancel_on_drop {
+                cancel_on_drop.abort();
             }
-            _ = timeout_task => {
-                    anyhow::bail!("Lsp Request time out");
+
+            match response {
+                Ok(Ok(message)) => serde_json::from_str(&message).map_err(|error| {
+                    log::error!(
+                        "Failed to deserialize the LSP response: {}. Error: {}",
+                        message,
+                        error
+                    );
+                    Error::Serialization(error)
+                }),
+                Ok(Err(Error::Response(error)))
+                    if error.code == error_codes::REQUEST_CANCELLED as i32 =>
+                {
+                    Err(Error::Cancelled(Some(error)))
                 }
+                Ok(Err(error)) => Err(error),
+                // The handler was dropped without being called, either the request had been
+                // cancelled or the server is gone
+                Err(_) if response_handlers.lock().is_none() => Err(Error::TransportClosed),
+                Err(_) => Err(Error::Cancelled(None)),
+            }
+        };
+
+        (cancel_handle, response)
+    }
+
+    pub(crate) fn set_default_reply(&self, method: &str, result: Option<Value>) {
+        let mut default_replies = self.default_replies.lock();
+        match result {
+            Some(result) => default_replies.insert(method.to_string(), result),
+            None => default_replies.remove(method),
+        };
+    }
+
+    pub(crate) fn update_timeouts(&self, update: impl FnOnce(&mut RequestTimeouts)) {
+        update(&mut self.timeouts.write())
+    }
+
+    // Fail the requests the check rejects with [Error::Unsupported], `None` sends every request
+    pub(crate) fn set_capability_check(&self, check: Option<CapabilityCheck>) {
+        *self.capability_check.write() = check;
+    }
+
+    fn check_capability<P: Serialize>(
+        &self,
+        method: &'static str,
+        params: &P,
+    ) -> Result<(), Error> {
+        let capability_check = self.capability_check.read();
+        let Some(check) = capability_check.as_ref() else {
+            return Ok(());
+        };
+
+        match check(method, &|| serde_json::to_value(params).ok()) {
+            true => Ok(()),
+            false => Err(Error::Unsupported { method }),
         }
     }
 
     pub(crate) async fn send_notification<T: notification::Notification>(
         &self,
         params: T::Params,
-    ) -> anyhow::Result<()> {
-        let message = serde_json::to_string(&LSPNotification {
-            jsonrpc: JSON_RPC_VERSION,
-            method: T::METHOD,
-            params,
-        })
-        .unwrap();
-
-        self.request_tx.send(message)?;
+    ) -> Result<(), Error> {
+        let is_initialized = T::METHOD == notification::Initialized::METHOD;
+        self.check_state(is_initialized || T::METHOD == notification::Exit::METHOD)?;
+
+        self.notifier().notify::<T>(params)?;
+
+        // The handshake is done, even if it was performed by hand
+        if is_initialized {
+            self.state.send_if_modified(|state| match state {
+                ServerState::Starting | ServerState::Initializing => {
+                    *state = ServerState::Running;
+                    true
+                }
+                _ => false,
+            });
+        }
 
         Ok(())
     }
 
+    pub(crate) fn notifier(&self) -> Notifier {
+        Notifier {
+            request_tx: self.request_tx.clone(),
+            state: self.state.clone(),
+            stderr_tail: self.stderr_tail.clone(),
+        }
+    }
+
+    pub(crate) fn state(&self) -> &watch::Sender<ServerState> {
+        &self.state
+    }
+
+    // Only lifecycle messages can be sent to the server before the handshake and during shutdown
+    pub(crate) fn check_state(&self, lifecycle: bool) -> Result<(), Error> {
+        check_state(&self.state, &self.stderr_tail, lifecycle)
+    }
+
     pub(crate) fn on_notification<T: notification::Notification, F>(&self, mut f: F) -> Subscription
     where
         T::Params: 'static + Send,
         F: Send + 'static + FnMut(T::Params),
     {
-        // Insert get the handler, this should return None
-        let prev_handler = self.notification_handlers.lock().insert(
-            T::METHOD,
-            Box::new(move |_, params| {
-                if let Ok(params) = serde_json::from_value(params) {
-                    f(params)
-                }
-            }),
-        );
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
 
-        assert!(
-            prev_handler.is_none(),
-            "Multiple handler for {} registered",
-            T::METHOD
-        );
+        self.notification_handlers
+            .lock()
+            .entry(T::METHOD)
+            .or_default()
+            .push((
+                id,
+                Arc::new(Mutex::new(Box::new(move |_, params| {
+                    if let Ok(params) = serde_json::from_value(params) {
+                        f(params)
+                    }
+                }))),
+            ));
 
         Subscription::Notification {
             method: T::METHOD,
-            notification_handlers: Some(self.notification_handlers.clone()),
+            id,
+            handlers: Some(Arc::downgrade(&self.notification_handlers)),
         }
     }
 
-    pub(crate) fn on_request<T: request::Request, F, Fut, Res>(&self, mut f: F) -> Subscription
+    pub(crate) fn notifications<T: notification::Notification>(
+        &self,
+        capacity: usize,
+        policy: LagPolicy,
+    ) -> NotificationStream<T::Params>
+    where
+        T::Params: 'static + Send,
+    {
+        let (sender, stream) = stream::channel(capacity, policy);
+        let subscription = self.on_notification::<T, _>(move |params| sender.send(params));
+
+        stream.with_subscription(subscription)
+    }
+
+    pub(crate) fn on_request<T: request::Request, F, Fut, Res>(&self, f: F) -> Subscription
     where
         T::Params: 'static + Send,
         F: Send + 'static + FnMut(T::Params) -> Fut,
         Fut: Send + 'static + Future<Output = anyhow::Result<Res>>,
         Res: Serialize,
     {
+        self.register_request::<T, F, Fut, Res>(&self.request_handlers, f)
+    }
+
+    // Answer a request on behalf of the client, unless a handler or a default reply answers it
+    pub(crate) fn on_fallback_request<T: request::Request, F, Fut, Res>(&self, f: F) -> Subscription
+    where
+        T::Params: 'static + Send,
+        F: Send + 'static + FnMut(T::Params) -> Fut,
+        Fut: Send + 'static + Future<Output = anyhow::Result<Res>>,
+        Res: Serialize,
+    {
+        self.register_request::<T, F, Fut, Res>(&self.fallback_handlers, f)
+    }
+
+    fn register_request<T: request::Request, F, Fut, Res>(
+        &self,
+        handlers: &Arc<Mutex<NotificationHandlers>>,
+        mut f: F,
+    ) -> Subscription
+    where
+        T::Params: 'static + Send,
+        F: Send + 'static + FnMut(T::Params) -> Fut,
+        Fut: Send + 'static + Future<Output = anyhow::Result<Res>>,
+        Res: Serialize,
+    {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
         let request_tx = self.request_tx.clone();
 
-        let prev_handler = self.notification_handlers.lock().insert(
-            T::METHOD,
-            Box::new(move |id, params| {
-                if let Some(id) = id {
-                    match serde_json::from_value::<T::Params>(params) {
-                        Ok(params) => {
-                            let result = f(params);
-
-                            tokio::spawn({
-                                let request_tx = request_tx.clone();
-                                async move {
-                                    let result = match result.await {
-                                        Ok(result) => LSPResponse {
-                                            jsonrpc: JSON_RPC_VERSION,
-                                            id,
-                                            value: crate::LSPResult::Ok(Some(result)),
-                                        },
-                                        Err(error) => LSPResponse {
-                                            jsonrpc: JSON_RPC_VERSION,
-                                            id,
-                                            value: crate::LSPResult::Err(Some(LSPError {
-                                                message: error.to_string(),
-                                                code: lsp_types::error_codes::REQUEST_FAILED as i32,
-                                                data: None,
-                                            })),
-                                        },
-                                    };
-
-                                    if let Ok(response) = serde_json::to_string(&result) {
-                                        request_tx.send(response).ok();
-                                    }
+        let handler: NotificationHandler = Box::new(move |id, params| {
+            if let Some(id) = id {
+                match serde_json::from_value::<T::Params>(params) {
+                    Ok(params) => {
+                        let result = f(params);
+
+                        tokio::spawn({
+                            let request_tx = request_tx.clone();
+                            async move {
+                                let result = match result.await {
+                                    Ok(result) => LSPResponse {
+                                        jsonrpc: JSON_RPC_VERSION,
+                                        id,
+                                        value: crate::LSPResult::Ok(Some(result)),
+                                    },
+                                    Err(error) => LSPResponse {
+                                        jsonrpc: JSON_RPC_VERSION,
+                                        id,
+                                        value: crate::LSPResult::Err(Some(LSPError {
+                                            message: error.to_string(),
+                                            code: lsp_types::error_codes::REQUEST_FAILED as i32,
+                                            data: None,
+                                        })),
+                                    },
+                                };
+
+                                if let Ok(response) = serde_json::to_string(&result) {
+                                    request_tx.send(response).ok();
                                 }
-                            });
-                        }
-                        Err(error) => {
-                            log::error!(
-                                "Failed to deserializing {} LSP request: {:?}",
-                                T::METHOD,
-                                error
-                            );
-                            let response = AnyResponse {
-                                jsonrpc: JSON_RPC_VERSION,
-                                id,
-                                result: None,
-                                error: Some(LSPError {
-                                    message: error.to_string(),
-                                    code: error_codes::UNKNOWN_ERROR_CODE as i32,
-                                    data: None,
-                                }),
-                            };
-
-                            if let Ok(response) = serde_json::to_string(&response) {
-                                request_tx.send(response).ok();
                             }
+                        });
+                    }
+                    Err(error) => {
+                        log::error!(
+                            "Failed to deserializing {} LSP request: {:?}",
+                            T::METHOD,
+                            error
+                        );
+                        let response = AnyResponse {
+                            jsonrpc: JSON_RPC_VERSION,
+                            id,
+                            result: None,
+                            error: Some(LSPError {
+                                message: error.to_string(),
+                                code: error_codes::UNKNOWN_ERROR_CODE as i32,
+                                data: None,
+                            }),
+                        };
+
+                        if let Ok(response) = serde_json::to_string(&response) {
+                            request_tx.send(response).ok();
                         }
                     }
                 }
-            }),
-        );
+            }
+        });
 
-        assert!(
-            prev_handler.is_none(),
-            "Multiple handler for {} registered",
-            T::METHOD
-        );
+        handlers
+            .lock()
+            .entry(T::METHOD)
+            .or_default()
+            .push((id, Arc::new(Mutex::new(handler))));
 
-        Subscription::Notification {
+        Subscription::Request {
             method: T::METHOD,
-            notification_handlers: Some(self.notification_handlers.clone()),
+            id,
+            handlers: Some(Arc::downgrade(handlers)),
         }
     }
 
@@ -301,24 +795,54 @@ impl Listener {
     where
         F: Send + 'static + FnMut(IOKind, &str),
     {
-        let id = self
-            .next_id
-            .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
 
         self.io_handlers.lock().insert(id, Box::new(f));
 
         Subscription::Io {
             id,
-            io_handlers: Some(Arc::downgrade(&self.io_handlers.clone())),
+            io_handlers: Some(Arc::downgrade(&self.io_handlers)),
         }
     }
 
-    pub(crate) fn kill(&mut self) -> anyhow::Result<()> {
+    pub(crate) fn kill(&self) -> anyhow::Result<()> {
         self.output_task.abort();
         drop(self.io_handlers.lock());
         drop(self.notification_handlers.lock());
+        drop(self.request_handlers.lock());
+        drop(self.fallback_handlers.lock());
         drop(self.response_handlers.lock());
 
         Ok(())
     }
 }
+
+// Serialize the parameters and add the progress token to them, under `field`
+fn with_token<P: Serialize>(params: P, field: &str, token: &ProgressToken) -> Result<Value, Error> {
+    let mut params = serde_json::to_value(params)?;
+
+    if let Some(params) = params.as_object_mut() {
+        params.insert(field.into(), serde_json::to_value(token)?);
+    }
+
+    Ok(params)
+}
+
+// Only lifecycle messages can be sent to the server before the handshake and during shutdown
+fn check_state(
+    state: &watch::Sender<ServerState>,
+    stderr_tail: &Mutex<VecDeque<String>>,
+    lifecycle: bool,
+) -> Result<(), Error> {
+    match *state.borrow() {
+        ServerState::Running => Ok(()),
+        ServerState::Starting | ServerState::Initializing if lifecycle => Ok(()),
+        ServerState::ShuttingDown if lifecycle => Ok(()),
+        ServerState::Starting | ServerState::Initializing => Err(Error::ServerNotInitialized),
+        ServerState::ShuttingDown => Err(Error::ShuttingDown),
+        ServerState::Exited(status) | ServerState::Crashed(status) => Err(Error::ServerExited {
+            status,
+            stderr: io::join_tail(stderr_tail),
+        }),
+    }
+}
@@ -1,8 +1,24 @@
+pub mod configuration;
+pub mod diagnostics;
+pub mod diff;
+pub mod document;
+pub mod encoding;
+mod error;
 pub(crate) mod io;
 pub(crate) mod listener;
 pub mod process;
+pub mod progress;
+pub mod registry;
+pub mod stream;
+pub mod supervisor;
+pub mod transport;
+#[cfg(all(feature = "watcher", target_os = "linux"))]
+pub(crate) mod watcher;
+pub mod workspace;
 use std::time::Duration;
 
+pub use error::Error;
+pub use listener::CancelHandle;
 pub use lsp_types;
 pub(crate) mod utils;
 use serde::{Deserialize, Serialize};
@@ -14,6 +30,7 @@ use serde_json::{value::RawValue, Value};
 /// Header and content part is seperated by \r\n\r\n
 
 // The version used by most Language server is 2.0
+#[allow(clippy::empty_line_after_doc_comments)]
 pub const JSON_RPC_VERSION: &str = "2.0";
 
 // Content length header
@@ -22,8 +39,27 @@ pub(crate) const CONTENT_LEN_HEADER: &str = "Content-Length: ";
 // Header and content seperator
 pub(crate) const HEADER_DELIMITER: &[u8; 4] = b"\r\n\r\n";
 
+// Error code of JSON RPC when the method is not found
+pub const METHOD_NOT_FOUND: i32 = -32601;
+
 pub(crate) const LSP_REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
 
+// Number of notifications buffered by a stream before the lag policy applies
+pub(crate) const NOTIFICATION_STREAM_CAPACITY: usize = 64;
+
+// Minimal time between two `workspace/diagnostic` requests, for servers answering right away
+pub(crate) const WORKSPACE_DIAGNOSTIC_INTERVAL: Duration = Duration::from_secs(1);
+
+// Time file events are gathered before being sent as one `workspace/didChangeWatchedFiles`
+#[cfg(all(feature = "watcher", target_os = "linux"))]
+pub(crate) const FILE_WATCH_DEBOUNCE: Duration = Duration::from_millis(200);
+
+// Time given to a spawned server to connect back to us
+pub(crate) const LSP_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Time given to the server to shutdown when it is dropped
+pub(crate) const LSP_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(1);
+
 /// Implemetation of LSP Request Id
 /// [See](https://microsoft.github.io/language-server-protocol/specifications/lsp/3.17/specification/#requestMessage)
 #[derive(Debug, Clone, Deserialize, Serialize, Eq, PartialEq, Hash)]
@@ -1,42 +1,109 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, VecDeque},
     ffi::OsString,
     future::Future,
     ops::DerefMut,
     path::{Path, PathBuf},
+    process::ExitStatus,
+    str::FromStr,
     sync::Arc,
+    time::Duration,
 };
 
-use lsp_types::{notification, request, CodeActionKind, ServerCapabilities};
+use lsp_types::{
+    notification, request, CodeActionKind, DiagnosticClientCapabilities,
+    DiagnosticWorkspaceClientCapabilities, InitializeParams, InitializeResult, InitializedParams,
+    PositionEncodingKind, ProgressToken, ServerCapabilities, ServerInfo, Uri,
+    WorkDoneProgressCancelParams,
+};
 use parking_lot::{Mutex, RwLock};
 use serde::Serialize;
-use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+use serde_json::Value;
+use tokio::sync::{
+    mpsc::{unbounded_channel, UnboundedReceiver},
+    watch,
+};
 
 use crate::IOKind;
 use crate::{
-    io::{IoHandler, NotificationHandler, ResponseHandler, IO},
-    listener::Listener,
-    utils::Subscription,
-    AnyNotification,
+    configuration::ConfigurationProvider,
+    diagnostics::{DiagnosticsPuller, DiagnosticsStore},
+    document::DocumentStore,
+    encoding,
+    io::{IoHandler, NotificationHandlers, ProcessHandle, ProgressHandler, ResponseHandler, IO},
+    listener::{CancelHandle, CapabilityCheck, Listener, Notifier},
+    progress::{Progress, ProgressEvent, ProgressTracker},
+    registry::CapabilityRegistry,
+    stream::{LagPolicy, NotificationStream, PartialResultStream},
+    transport::{Connection, Transport},
+    utils::{self, Subscription},
+    workspace::WorkspaceFolders,
+    AnyNotification, Error, LSP_SHUTDOWN_TIMEOUT, NOTIFICATION_STREAM_CAPACITY,
 };
+#[cfg(all(feature = "watcher", target_os = "linux"))]
+use crate::{watcher::FileWatcher, FILE_WATCH_DEBOUNCE};
+#[cfg(all(feature = "watcher", target_os = "linux"))]
+use lsp_types::DidChangeWatchedFilesClientCapabilities;
+#[cfg(all(feature = "watcher", target_os = "linux"))]
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 /// Binary of the language server
 ///
 /// * `path`: path to the executable
 /// * `envs`: List of environment variables
 /// * `args`: List of arguments for starting the process
+#[derive(Debug, Clone)]
 pub struct LanguageServerBinary {
     pub path: PathBuf,
     pub envs: Option<HashMap<String, String>>,
     pub args: Vec<OsString>,
 }
 
+/// Lifecycle of the language server
+///
+/// * `Starting`: The process is spawned, the initialize handshake isn't done
+/// * `Initializing`: `initialize` had been sent, waiting for the response
+/// * `Running`: The handshake is done, every request can be sent
+/// * `ShuttingDown`: `shutdown` had been sent, only `exit` can be sent
+/// * `Exited`: The server exited after a shutdown
+/// * `Crashed`: The server exited without being shut down
+///
+/// The exit status is `None` for a connection without process, the server is gone once the
+/// connection is closed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ServerState {
+    Starting,
+    Initializing,
+    Running,
+    ShuttingDown,
+    Exited(Option<ExitStatus>),
+    Crashed(Option<ExitStatus>),
+}
+
+impl ServerState {
+    /// Whether the process is gone
+    pub fn is_terminated(&self) -> bool {
+        matches!(self, ServerState::Exited(_) | ServerState::Crashed(_))
+    }
+}
+
 pub struct LanguageServer {
     io: IO,
-    listener: Listener,
+    listener: Arc<Listener>,
     pub output_done_rx: UnboundedReceiver<String>,
     code_action_kind: Option<Vec<CodeActionKind>>,
-    capabilities: RwLock<ServerCapabilities>,
+    capabilities: Arc<RwLock<ServerCapabilities>>,
+    documents: DocumentStore,
+    diagnostics: DiagnosticsStore,
+    puller: DiagnosticsPuller,
+    registry: CapabilityRegistry,
+    configuration: ConfigurationProvider,
+    workspace_folders: WorkspaceFolders,
+    server_info: RwLock<Option<ServerInfo>>,
+    progress: ProgressTracker,
+    // Number of running file watchers, see [LanguageServer::watch_files]
+    #[cfg(all(feature = "watcher", target_os = "linux"))]
+    file_watchers: Arc<AtomicUsize>,
 }
 
 impl LanguageServer {
@@ -66,46 +133,264 @@ impl LanguageServer {
         root_path: &Path,
         capture: Arc<Mutex<Option<String>>>,
         code_action_kind: Option<Vec<CodeActionKind>>,
+    ) -> anyhow::Result<Self> {
+        let connection = Connection::spawn(binary, utils::working_dir(root_path))?;
+        Self::with_connection(connection, id, root_path, capture, code_action_kind)
+    }
+
+    /// Start a language server over any [Transport]: a spawned process, a TCP or Unix socket,
+    /// a listener the server connects back to, or any reader/writer pair
+    ///
+    /// # Usage
+    /// ``` rust
+    ///     let transport = Transport::tcp("127.0.0.1:6005").await?;
+    ///     let server =
+    ///         LanguageServer::connect(transport, 1, root_path, stderr_capture, None).await?;
+    /// ```
+    /// * `transport`: See [Transport]
+    /// * `id`: id for the server
+    /// * `root_path`: Root path for the lsp, useful for discovering workspaces
+    /// * `capture`: Stderr capturer, only used when the server is spawned
+    /// * `code_action_kind`: List of code action kinds that will be registered during startup
+    pub async fn connect(
+        transport: Transport,
+        id: i32,
+        root_path: &Path,
+        capture: Arc<Mutex<Option<String>>>,
+        code_action_kind: Option<Vec<CodeActionKind>>,
+    ) -> anyhow::Result<Self> {
+        let connection = transport.connect(utils::working_dir(root_path)).await?;
+        Self::with_connection(connection, id, root_path, capture, code_action_kind)
+    }
+
+    fn with_connection(
+        connection: Connection,
+        id: i32,
+        root_path: &Path,
+        capture: Arc<Mutex<Option<String>>>,
+        code_action_kind: Option<Vec<CodeActionKind>>,
     ) -> anyhow::Result<Self> {
         let (request_tx, request_rx) = unbounded_channel::<String>();
         let (notification_tx, notification_rx) = unbounded_channel::<AnyNotification>();
         let (output_done_tx, output_done_rx) = unbounded_channel();
 
-        let notification_handlers =
-            Arc::new(Mutex::new(HashMap::<_, NotificationHandler>::default()));
+        let notification_handlers = Arc::new(Mutex::new(NotificationHandlers::default()));
         let response_handlers =
             Arc::new(Mutex::new(Some(HashMap::<_, ResponseHandler>::default())));
+        let progress_handlers = Arc::new(Mutex::new(HashMap::<_, ProgressHandler>::default()));
 
         let io_handlers = Arc::new(Mutex::new(HashMap::<_, IoHandler>::default()));
+        let (state, _) = watch::channel(ServerState::Starting);
+        let stderr_tail = Arc::new(Mutex::new(VecDeque::new()));
 
         let io = IO::new(
             id,
-            binary,
+            connection,
             response_handlers.clone(),
+            progress_handlers.clone(),
             io_handlers.clone(),
             request_rx,
             notification_tx,
             output_done_tx,
             root_path,
             capture,
+            state.clone(),
+            stderr_tail.clone(),
         )?;
-        let listener = Listener::new(
+        let listener = Arc::new(Listener::new(
             notification_rx,
             notification_handlers,
             response_handlers,
+            progress_handlers,
             io_handlers,
             request_tx,
-        )?;
+            state,
+            stderr_tail,
+        )?);
+        let progress = ProgressTracker::new(&listener);
+        let capabilities = Arc::new(RwLock::new(ServerCapabilities::default()));
+        let documents = DocumentStore::new(capabilities.clone(), listener.notifier());
+        let diagnostics = DiagnosticsStore::new(&listener);
+        let puller = DiagnosticsPuller::new(
+            &listener,
+            capabilities.clone(),
+            diagnostics.clone(),
+            &documents,
+        );
+        let registry = CapabilityRegistry::new(&listener);
+        let configuration = ConfigurationProvider::new(&listener);
+        let workspace_folders =
+            WorkspaceFolders::new(&listener, capabilities.clone(), registry.clone(), root_path);
 
         Ok(Self {
             io,
             listener,
             output_done_rx,
             code_action_kind,
-            capabilities: Default::default(),
+            capabilities,
+            documents,
+            diagnostics,
+            puller,
+            registry,
+            configuration,
+            workspace_folders,
+            server_info: Default::default(),
+            progress,
+            #[cfg(all(feature = "watcher", target_os = "linux"))]
+            file_watchers: Default::default(),
         })
     }
 
+    /// Perform the initialize handshake: send `initialize`, store the [ServerCapabilities] and
+    /// [ServerInfo] of the result, then send `initialized`.
+    /// Until the handshake is done, every request other than `initialize` fail with
+    /// [Error::ServerNotInitialized], as the specification requires. The handshake is only
+    /// performed once, it fails with [Error::AlreadyInitialized] once started, and with
+    /// [Error::ServerExited] if the server is gone
+    ///
+    /// The process id and root uri are filled when missing, the root uri from the root path or its
+    /// directory when it is a file, the workspace folders from [LanguageServer::workspace_folders]
+    ///
+    /// # Usage
+    /// ```rust
+    ///     let result = server.initialize(InitializeParams::default()).await?;
+    ///     assert_eq!(server.capabilities(), result.capabilities);
+    /// ```
+    /// * `params`: Parameters for the `initialize` request
+    #[allow(deprecated)]
+    pub async fn initialize(
+        &self,
+        mut params: InitializeParams,
+    ) -> Result<InitializeResult, Error> {
+        let root_uri = utils::path_to_uri(utils::working_dir(self.root_path()));
+
+        if params.process_id.is_none() {
+            params.process_id = Some(std::process::id());
+        }
+
+        if params.root_uri.is_none() {
+            params.root_uri = root_uri.clone();
+        }
+
+        // The folders named by the params replace the ones added before the handshake
+        match &params.workspace_folders {
+            Some(folders) => self.workspace_folders.set(folders.clone()),
+            None => {
+                let folders = self.workspace_folders.folders();
+                params.workspace_folders = (!folders.is_empty()).then_some(folders);
+            }
+        }
+
+        // Positions can be converted from any encoding, prefer the one of Rust strings
+        let general = params
+            .capabilities
+            .general
+            .get_or_insert_with(Default::default);
+        if general.position_encodings.is_none() {
+            general.position_encodings = Some(vec![
+                PositionEncodingKind::UTF8,
+                PositionEncodingKind::UTF16,
+                PositionEncodingKind::UTF32,
+            ]);
+        }
+
+        // Progress is tracked, let the server report it
+        let window = params
+            .capabilities
+            .window
+            .get_or_insert_with(Default::default);
+        if window.work_done_progress.is_none() {
+            window.work_done_progress = Some(true);
+        }
+
+        // Diagnostics are pulled when the server supports it, see [DiagnosticsStore]
+        let text_document = params
+            .capabilities
+            .text_document
+            .get_or_insert_with(Default::default);
+        if text_document.diagnostic.is_none() {
+            text_document.diagnostic = Some(DiagnosticClientCapabilities {
+                related_document_support: Some(true),
+                ..Default::default()
+            });
+        }
+
+        // Settings are answered and pushed, see [ConfigurationProvider]
+        let workspace = params
+            .capabilities
+            .workspace
+            .get_or_insert_with(Default::default);
+        if workspace.configuration.is_none() {
+            workspace.configuration = Some(true);
+        }
+        if workspace.workspace_folders.is_none() {
+            workspace.workspace_folders = Some(true);
+        }
+
+        // Watched files are registered by the server, see [LanguageServer::watch_files]. Without
+        // watcher the server keeps watching the files by itself
+        #[cfg(all(feature = "watcher", target_os = "linux"))]
+        if workspace.did_change_watched_files.is_none()
+            && self.file_watchers.load(Ordering::SeqCst) > 0
+        {
+            workspace.did_change_watched_files = Some(DidChangeWatchedFilesClientCapabilities {
+                dynamic_registration: Some(true),
+                relative_pattern_support: Some(true),
+            });
+        }
+        if workspace.diagnostic.is_none() {
+            workspace.diagnostic = Some(DiagnosticWorkspaceClientCapabilities {
+                refresh_support: Some(true),
+            });
+        }
+
+        // Only a server which never started the handshake can be initialized
+        let state = self.listener.state();
+        let mut previous = ServerState::Starting;
+        let starting = state.send_if_modified(|state| {
+            previous = *state;
+            let starting = *state == ServerState::Starting;
+            if starting {
+                *state = ServerState::Initializing;
+            }
+            starting
+        });
+        if !starting {
+            return match previous {
+                ServerState::Initializing | ServerState::Running => Err(Error::AlreadyInitialized),
+                // Shutting down or gone
+                _ => Err(self
+                    .listener
+                    .check_state(false)
+                    .err()
+                    .unwrap_or(Error::AlreadyInitialized)),
+            };
+        }
+
+        let result = match self.request::<request::Initialize>(params).await {
+            Ok(result) => result,
+            Err(error) => {
+                state.send_if_modified(|state| {
+                    let initializing = *state == ServerState::Initializing;
+                    if initializing {
+                        *state = ServerState::Starting;
+                    }
+                    initializing
+                });
+                return Err(error);
+            }
+        };
+
+        *self.capabilities.write() = result.capabilities.clone();
+        self.registry.set_capabilities(&result.capabilities);
+        *self.server_info.write() = result.server_info.clone();
+
+        self.notify::<notification::Initialized>(InitializedParams {})
+            .await?;
+
+        Ok(result)
+    }
+
     /// Send a request to the server and get the response back
     /// T must be type of [request::Request]. We had re-exported the module
     ///
@@ -116,14 +401,239 @@ impl LanguageServer {
     ///     let init_params = IntializeParams::default();
     ///     let response = server.request::<Initialize>(init_params)?;
     /// ```
+    /// Requests other than `initialize` fail with [Error::ServerNotInitialized] until the
+    /// handshake is done, see [LanguageServer::initialize].
+    /// Dropping the returned future before the response arrived will cancel the request,
+    /// see [LanguageServer::request_cancellable]
+    /// Document changes held by [DocumentStore::set_coalesce_window] are sent first
+    ///
     /// * `params`: Parameters for the request
     pub async fn request<T: request::Request>(
         &self,
         params: T::Params,
-    ) -> anyhow::Result<T::Result> {
+    ) -> Result<T::Result, Error> {
+        self.flush_documents();
         self.listener.request::<T>(params).await
     }
 
+    /// Send a request to the server and get back a [CancelHandle] along with the response future.
+    /// The request is written to the server right away, the future only wait for the response.
+    /// Cancelling the handle or dropping the future will remove the pending response handler
+    /// and send `$/cancelRequest` to the server, the future then resolves to [Error::Cancelled]
+    ///
+    /// # Usage
+    /// ```rust
+    ///     use chan_rs::lsp_types::request::Completion;
+    ///
+    ///     let (handle, response) = server.request_cancellable::<Completion>(params);
+    ///     // The user typed another character, the completion is stale
+    ///     handle.cancel();
+    ///     assert!(matches!(response.await, Err(Error::Cancelled(None))));
+    /// ```
+    /// * `params`: Parameters for the request
+    pub fn request_cancellable<T: request::Request>(
+        &self,
+        params: T::Params,
+    ) -> (
+        CancelHandle,
+        impl Future<Output = Result<T::Result, Error>> + Send + 'static,
+    ) {
+        self.flush_documents();
+        self.listener.request_cancellable::<T>(params)
+    }
+
+    /// Send a request to the server, waiting at most `timeout` for the response instead of the
+    /// configured timeout of the method. See [LanguageServer::set_request_timeout]
+    ///
+    /// * `params`: Parameters for the request
+    /// * `timeout`: Time to wait for the response
+    pub async fn request_with_timeout<T: request::Request>(
+        &self,
+        params: T::Params,
+        timeout: Duration,
+    ) -> Result<T::Result, Error> {
+        self.flush_documents();
+        let (_, response) = self
+            .listener
+            .request_with_timeout::<T>(params, Some(timeout));
+        response.await
+    }
+
+    /// Send a request to the server and wait for the response for as long as it takes
+    /// The request can still be cancelled by dropping the future
+    ///
+    /// * `params`: Parameters for the request
+    pub async fn request_without_timeout<T: request::Request>(
+        &self,
+        params: T::Params,
+    ) -> Result<T::Result, Error> {
+        self.flush_documents();
+        let (_, response) = self.listener.request_with_timeout::<T>(params, None);
+        response.await
+    }
+
+    /// Send a request supporting partial results, eg. `workspace/symbol` or
+    /// `textDocument/references`, and receive the results as they arrive
+    /// A fresh `partialResultToken` is added to the parameters, the stream yields every batch
+    /// reported through `$/progress` then ends with the final response. Array results are
+    /// concatenated, the final response then holds every item of the batches
+    /// The configured timeout of the method applies to the whole request
+    ///
+    /// # Usage
+    /// ```rust
+    ///     use futures::StreamExt;
+    ///     use chan_rs::lsp_types::request::WorkspaceSymbolRequest;
+    ///
+    ///     let mut symbols = server.request_streaming::<WorkspaceSymbolRequest>(params);
+    ///     while let Some(result) = symbols.next().await {
+    ///         match result? {
+    ///             PartialResult::Partial(batch) => { ... }
+    ///             PartialResult::Done(all) => { ... }
+    ///         }
+    ///     }
+    /// ```
+    /// * `params`: Parameters for the request
+    pub fn request_streaming<T: request::Request>(
+        &self,
+        params: T::Params,
+    ) -> PartialResultStream<T::Result> {
+        self.flush_documents();
+        self.listener.request_streaming::<T>(params)
+    }
+
+    /// Send a request supporting work done progress, eg. `textDocument/formatting` or
+    /// `workspace/executeCommand`, and follow the progress the server reports for it
+    /// A fresh `workDoneToken` is added to the parameters, the stream only yields the progress
+    /// of this token and ends once the response arrived. The progress is also visible through
+    /// [LanguageServer::active_progress] and [LanguageServer::progress_events]
+    ///
+    /// # Usage
+    /// ```rust
+    ///     use futures::StreamExt;
+    ///     use chan_rs::lsp_types::request::ExecuteCommand;
+    ///
+    ///     let (response, mut progress) = server.request_with_progress::<ExecuteCommand>(params);
+    ///     tokio::spawn(async move {
+    ///         while let Some(event) = progress.next().await {
+    ///             ...
+    ///         }
+    ///     });
+    ///     let result = response.await?;
+    /// ```
+    /// * `params`: Parameters for the request
+    pub fn request_with_progress<T: request::Request>(
+        &self,
+        params: T::Params,
+    ) -> (
+        impl Future<Output = Result<T::Result, Error>> + Send + 'static,
+        NotificationStream<ProgressEvent>,
+    ) {
+        self.flush_documents();
+        self.listener.request_with_progress::<T>(
+            params,
+            NOTIFICATION_STREAM_CAPACITY,
+            LagPolicy::default(),
+        )
+    }
+
+    /// Set the default timeout of every request, methods with their own timeout are not affected
+    /// `None` disable the timeout. The default is 5 seconds
+    ///
+    /// # Usage
+    /// ```rust
+    ///     let server = LanguageServer::new(binary, 1, root_path, stderr_capture, None)?
+    ///         .with_request_timeout(Some(Duration::from_secs(30)));
+    /// ```
+    /// * `timeout`: Time to wait for responses
+    pub fn with_request_timeout(self, timeout: Option<Duration>) -> Self {
+        self.set_request_timeout(timeout);
+        self
+    }
+
+    /// Set the default timeout of every request, see [LanguageServer::with_request_timeout]
+    ///
+    /// * `timeout`: Time to wait for responses, `None` wait forever
+    pub fn set_request_timeout(&self, timeout: Option<Duration>) {
+        self.listener
+            .update_timeouts(|timeouts| timeouts.default = timeout)
+    }
+
+    /// Override the timeout of one method, eg. `workspace/symbol` on a big project
+    ///
+    /// # Usage
+    /// ```rust
+    ///     use chan_rs::lsp_types::request::WorkspaceSymbolRequest;
+    ///
+    ///     server.set_method_timeout::<WorkspaceSymbolRequest>(Some(Duration::from_secs(60)));
+    /// ```
+    /// * `timeout`: Time to wait for responses of this method, `None` wait forever
+    pub fn set_method_timeout<T: request::Request>(&self, timeout: Option<Duration>) {
+        self.listener.update_timeouts(|timeouts| {
+            timeouts.methods.insert(T::METHOD, timeout);
+        })
+    }
+
+    /// Remove the timeout override of one method, it will use the default timeout again
+    pub fn clear_method_timeout<T: request::Request>(&self) {
+        self.listener.update_timeouts(|timeouts| {
+            timeouts.methods.remove(T::METHOD);
+        })
+    }
+
+    /// Fail the requests the server doesn't support right away with [Error::Unsupported],
+    /// instead of sending them. Support is checked as [LanguageServer::supports] does, against
+    /// the `textDocument` of the params. Disabled by default
+    ///
+    /// # Usage
+    /// ```rust
+    ///     use chan_rs::lsp_types::request::InlayHintRequest;
+    ///
+    ///     let server = LanguageServer::new(binary, 1, root_path, stderr_capture, None)?
+    ///         .with_capability_check(true);
+    ///     server.initialize(InitializeParams::default()).await?;
+    ///
+    ///     match server.request::<InlayHintRequest>(params).await {
+    ///         Err(Error::Unsupported { .. }) => { ... }
+    ///         result => { ... }
+    ///     }
+    /// ```
+    /// * `check`: Whether to check the requests
+    pub fn with_capability_check(self, check: bool) -> Self {
+        self.set_capability_check(check);
+        self
+    }
+
+    /// Whether to check the requests, see [LanguageServer::with_capability_check]
+    pub fn set_capability_check(&self, check: bool) {
+        let check = check.then(|| {
+            let registry = self.registry.clone();
+            let documents = self.documents.clone();
+
+            Box::new(move |method: &str, params: &dyn Fn() -> Option<Value>| {
+                if !CapabilityRegistry::declares(method) {
+                    return true;
+                }
+
+                let uri = params()
+                    .as_ref()
+                    .and_then(|params| params.pointer("/textDocument/uri"))
+                    .and_then(Value::as_str)
+                    .and_then(|uri| Uri::from_str(uri).ok());
+
+                registry.supports(method, uri.as_ref(), &documents)
+            }) as CapabilityCheck
+        });
+
+        self.listener.set_capability_check(check)
+    }
+
+    /// Whether to send `$/cancelRequest` to the server when a request time out.
+    /// The response handler is always removed. Enabled by default
+    pub fn set_cancel_on_timeout(&self, cancel: bool) {
+        self.listener
+            .update_timeouts(|timeouts| timeouts.cancel_on_timeout = cancel)
+    }
+
     /// Send a notify to the server, notify requests don't send response back
     /// T must be type of [notification::Notification]. We had re-exported the module
     ///
@@ -139,15 +649,23 @@ impl LanguageServer {
     pub async fn notify<T: notification::Notification>(
         &self,
         params: T::Params,
-    ) -> anyhow::Result<()> {
+    ) -> Result<(), Error> {
+        self.flush_documents();
         self.listener.send_notification::<T>(params).await
     }
 
+    // Send the changes held by the document store, the server must never see stale documents
+    fn flush_documents(&self) {
+        if let Err(error) = self.documents.flush_all() {
+            log::warn!("Failed to send the document changes: {}", error);
+        }
+    }
+
     /// Most of the request types are straightforward enough, you send request and then get the response back, and you're done.
     /// But some of them like [workspace/willCreateFiles] have their associate notification method eg.[workspace/didCreateFiles]
     /// For those request, you can register a handler that automatically send the notification.
-    /// You can only register on handler per method. If you register more than one, the program
-    /// will panic
+    /// Only one handler answers a request: the last one registered for the method. Dropping its
+    /// [Subscription] gives the method back to the previous handler
     pub fn on_request<T: request::Request, F, Fut, Res>(&self, f: F) -> Subscription
     where
         T::Params: 'static + Send,
@@ -159,7 +677,18 @@ impl LanguageServer {
     }
 
     /// Register a handler to handle incoming notification
-    /// You can only register one handler for one method
+    /// Every handler registered for the method is called, in registration order. The handler is
+    /// removed when the returned [Subscription] is dropped, unless it is detached
+    ///
+    /// # Usage
+    /// ```rust
+    ///     use chan_rs::lsp_types::notification::PublishDiagnostics;
+    ///
+    ///     let panel = server.on_notification::<PublishDiagnostics, _>(|params| { ... });
+    ///     server
+    ///         .on_notification::<PublishDiagnostics, _>(|params| { ... })
+    ///         .detach();
+    /// ```
     pub fn on_notification<T: notification::Notification, F>(&self, f: F) -> Subscription
     where
         T::Params: 'static + Send,
@@ -168,8 +697,112 @@ impl LanguageServer {
         self.listener.on_notification::<T, F>(f)
     }
 
+    /// Receive the notifications of a method as a [futures::Stream], instead of a callback
+    /// At most 64 notifications are buffered, the oldest are dropped when the consumer lags behind
+    /// The stream ends when the server exits
+    ///
+    /// # Usage
+    /// ```rust
+    ///     use futures::StreamExt;
+    ///     use chan_rs::lsp_types::notification::PublishDiagnostics;
+    ///
+    ///     let mut diagnostics = server.notifications::<PublishDiagnostics>();
+    ///     while let Some(params) = diagnostics.next().await {
+    ///         ...
+    ///     }
+    /// ```
+    pub fn notifications<T: notification::Notification>(&self) -> NotificationStream<T::Params>
+    where
+        T::Params: 'static + Send,
+    {
+        self.notifications_with::<T>(NOTIFICATION_STREAM_CAPACITY, LagPolicy::default())
+    }
+
+    /// Receive the notifications of a method as a [futures::Stream], see
+    /// [LanguageServer::notifications]
+    ///
+    /// * `capacity`: Number of notifications buffered
+    /// * `policy`: What to drop when the buffer is full, see [LagPolicy]
+    pub fn notifications_with<T: notification::Notification>(
+        &self,
+        capacity: usize,
+        policy: LagPolicy,
+    ) -> NotificationStream<T::Params>
+    where
+        T::Params: 'static + Send,
+    {
+        self.listener.notifications::<T>(capacity, policy)
+    }
+
+    /// Work done progress currently reported by the server, keyed by their token
+    /// `window/workDoneProgress/create` is answered by default, register a handler with
+    /// [LanguageServer::on_request] to override it
+    pub fn active_progress(&self) -> HashMap<ProgressToken, Progress> {
+        self.progress.active()
+    }
+
+    /// State of one work done progress, `None` if it had not begun or had ended
+    pub fn progress(&self, token: &ProgressToken) -> Option<Progress> {
+        self.progress.get(token)
+    }
+
+    /// Receive the changes of the work done progress as a stream
+    ///
+    /// # Usage
+    /// ```rust
+    ///     use futures::StreamExt;
+    ///
+    ///     let mut events = server.progress_events();
+    ///     while let Some(event) = events.next().await {
+    ///         match event {
+    ///             ProgressEvent::Begin { token, progress } => { ... }
+    ///             ProgressEvent::Report { token, progress } => { ... }
+    ///             ProgressEvent::End { token, message } => { ... }
+    ///         }
+    ///     }
+    /// ```
+    pub fn progress_events(&self) -> NotificationStream<ProgressEvent> {
+        self.progress.events(
+            &self.listener,
+            NOTIFICATION_STREAM_CAPACITY,
+            LagPolicy::default(),
+        )
+    }
+
+    /// Ask the server to cancel a work done progress with `window/workDoneProgress/cancel`
+    /// Only progress reported as cancellable can be cancelled, see [Progress]
+    pub async fn cancel_progress(&self, token: ProgressToken) -> Result<(), Error> {
+        self.notify::<notification::WorkDoneProgressCancel>(WorkDoneProgressCancelParams { token })
+            .await
+    }
+
+    /// Reply `result` to the requests of the server without handler for this method, instead of
+    /// responding `MethodNotFound` (-32601). Handlers registered with [LanguageServer::on_request]
+    /// take precedence. The default reply takes precedence over the requests the client answers
+    /// by itself, like `workspace/configuration` answered by [ConfigurationProvider]
+    ///
+    /// # Usage
+    /// ```rust
+    ///     use chan_rs::lsp_types::request::WorkspaceConfiguration;
+    ///
+    ///     server.set_default_reply::<WorkspaceConfiguration>(vec![Value::Null]);
+    /// ```
+    /// * `result`: Result sent back to the server
+    pub fn set_default_reply<T: request::Request>(&self, result: T::Result) {
+        match serde_json::to_value(result) {
+            Ok(result) => self.listener.set_default_reply(T::METHOD, Some(result)),
+            Err(error) => log::error!("Invalid default reply for {}: {}", T::METHOD, error),
+        }
+    }
+
+    /// Remove the default reply of a method, its requests get `MethodNotFound` again, or the
+    /// answer of the client if it answers them by itself
+    pub fn remove_default_reply<T: request::Request>(&self) {
+        self.listener.set_default_reply(T::METHOD, None)
+    }
+
     /// Register a handler to the process's io task
-    /// You can re-regsiter the handler
+    /// The handler is removed when the returned [Subscription] is dropped, unless it is detached
     pub fn on_io<F>(&self, f: F) -> Subscription
     where
         F: Send + 'static + FnMut(IOKind, &str),
@@ -177,6 +810,22 @@ impl LanguageServer {
         self.listener.on_io::<F>(f)
     }
 
+    /// Current lifecycle state of the server
+    pub fn state(&self) -> ServerState {
+        *self.listener.state().borrow()
+    }
+
+    /// Watch the lifecycle state of the server
+    ///
+    /// # Usage
+    /// ```rust
+    ///     let mut state = server.subscribe_state();
+    ///     state.wait_for(ServerState::is_terminated).await?;
+    /// ```
+    pub fn subscribe_state(&self) -> watch::Receiver<ServerState> {
+        self.listener.state().subscribe()
+    }
+
     /// Get the server id
     pub fn server_id(&self) -> i32 {
         self.io.id()
@@ -197,9 +846,151 @@ impl LanguageServer {
         self.capabilities.read().clone()
     }
 
+    /// Encoding of the positions exchanged with the server, negotiated by
+    /// [LanguageServer::initialize]. UTF-16 if the server didn't pick one
+    /// See [crate::encoding] to convert positions
+    pub fn position_encoding(&self) -> PositionEncodingKind {
+        encoding::negotiated(&self.capabilities.read())
+    }
+
+    /// Name and version of the server, known after [LanguageServer::initialize]
+    pub fn server_info(&self) -> Option<ServerInfo> {
+        self.server_info.read().clone()
+    }
+
     /// Update the server capabilities
     pub fn update_capabilities(&self, update: impl FnOnce(&mut ServerCapabilities)) {
-        update(self.capabilities.write().deref_mut())
+        let mut capabilities = self.capabilities.write();
+        update(capabilities.deref_mut());
+        self.registry.set_capabilities(&capabilities);
+    }
+
+    /// The capabilities registered dynamically by the server, see [CapabilityRegistry]
+    pub fn registrations(&self) -> &CapabilityRegistry {
+        &self.registry
+    }
+
+    /// Whether the server supports a request, from its [ServerCapabilities] or a capability it
+    /// registered dynamically. Requests which support isn't declared by a capability, like
+    /// `shutdown`, are always supported
+    ///
+    /// # Usage
+    /// ```rust
+    ///     use chan_rs::lsp_types::request::Formatting;
+    ///
+    ///     if server.supports::<Formatting>(Some(&uri)) {
+    ///         let edits = server.request::<Formatting>(params).await?;
+    ///     }
+    /// ```
+    /// * `uri`: Document the request is about, matched against the document selectors of the
+    ///   capabilities. The language of the document is known if it was opened through
+    ///   [LanguageServer::documents], otherwise the languages of the selectors are ignored.
+    ///   `None` ignores the document selectors
+    pub fn supports<T: request::Request>(&self, uri: Option<&Uri>) -> bool {
+        self.registry.supports(T::METHOD, uri, &self.documents)
+    }
+
+    /// The settings sent to the server, see [ConfigurationProvider]
+    pub fn configuration(&self) -> &ConfigurationProvider {
+        &self.configuration
+    }
+
+    /// The workspace folders opened on the server, see [WorkspaceFolders]
+    pub fn workspace_folders(&self) -> &WorkspaceFolders {
+        &self.workspace_folders
+    }
+
+    /// Watch the files the server registered for `workspace/didChangeWatchedFiles`, and send
+    /// their changes. Changes are gathered for 200ms then sent as one notification, see
+    /// [LanguageServer::watch_files_with]
+    /// Requires the `watcher` feature, Linux only
+    ///
+    /// The client only declares `workspace/didChangeWatchedFiles` support in the `initialize`
+    /// request while files are watched, start watching before the handshake
+    ///
+    /// # Usage
+    /// ```rust
+    ///     // Watches until the subscription is dropped
+    ///     let subscription = server.watch_files()?;
+    ///     server.initialize(InitializeParams::default()).await?;
+    /// ```
+    #[cfg(all(feature = "watcher", target_os = "linux"))]
+    pub fn watch_files(&self) -> std::io::Result<Subscription> {
+        self.watch_files_with(FILE_WATCH_DEBOUNCE)
+    }
+
+    /// Watch the files the server registered, see [LanguageServer::watch_files]
+    /// The watched directories follow the registrations and the
+    /// [LanguageServer::workspace_folders]: relative globs apply to every folder. The
+    /// `WatchKind` of the watchers is honored, and the changes of a file within the same batch
+    /// are merged, e.g. a file created then deleted isn't sent
+    ///
+    /// * `debounce`: Time the changes are gathered before being sent
+    #[cfg(all(feature = "watcher", target_os = "linux"))]
+    pub fn watch_files_with(&self, debounce: Duration) -> std::io::Result<Subscription> {
+        let watcher = FileWatcher::new(
+            self.registry.clone(),
+            self.workspace_folders.clone(),
+            self.listener.notifier(),
+            debounce,
+        )?;
+
+        let file_watchers = self.file_watchers.clone();
+        file_watchers.fetch_add(1, Ordering::SeqCst);
+
+        let task = tokio::spawn(async move {
+            let _running = utils::defer(move || {
+                file_watchers.fetch_sub(1, Ordering::SeqCst);
+            });
+            watcher.run().await
+        });
+        Ok(Subscription::Task {
+            task: Some(task.abort_handle()),
+        })
+    }
+
+    /// The documents opened on the server, see [DocumentStore]
+    pub fn documents(&self) -> &DocumentStore {
+        &self.documents
+    }
+
+    /// The diagnostics published by the server, see [DiagnosticsStore]
+    pub fn diagnostics(&self) -> &DiagnosticsStore {
+        &self.diagnostics
+    }
+
+    /// Pull the diagnostics of a document with `textDocument/diagnostic` and store them in
+    /// [LanguageServer::diagnostics]
+    /// Opened documents are already pulled after every change, and again when the server sends
+    /// `workspace/diagnostic/refresh`. Does nothing if the server doesn't support pull diagnostics
+    ///
+    /// # Usage
+    /// ```rust
+    ///     server.pull_diagnostics(&uri).await?;
+    ///     let errors = server.diagnostics().counts().errors;
+    /// ```
+    /// * `uri`: Document to pull, the result id of the previous report is sent along
+    pub async fn pull_diagnostics(&self, uri: &Uri) -> Result<(), Error> {
+        self.flush_documents();
+        self.puller.pull(uri.clone()).await
+    }
+
+    /// Keep `workspace/diagnostic` running in the background, storing the reports in
+    /// [LanguageServer::diagnostics]
+    /// The request is sent again once answered, and restarted when the server sends
+    /// `workspace/diagnostic/refresh`. Does nothing if the server doesn't support workspace
+    /// diagnostics
+    ///
+    /// # Usage
+    /// ```rust
+    ///     // Polls until the subscription is dropped
+    ///     let subscription = server.workspace_diagnostics();
+    /// ```
+    pub fn workspace_diagnostics(&self) -> Subscription {
+        let task = tokio::spawn(self.puller.clone().poll_workspace());
+        Subscription::Task {
+            task: Some(task.abort_handle()),
+        }
     }
 
     /// List code action kinds
@@ -212,6 +1003,43 @@ impl LanguageServer {
         self.io.name()
     }
 
+    /// Gracefully stop the server: send `shutdown` and wait for the response, send `exit` and
+    /// wait for the process to exit. If the process is still alive after `grace`, it is sent
+    /// SIGTERM, then SIGKILL if it is still alive after another `grace`. The exit status is
+    /// `None` for a connection without process, once the connection is closed
+    ///
+    /// # Usage
+    /// ```rust
+    ///     let status = server.shutdown(Duration::from_secs(2)).await?;
+    /// ```
+    /// * `grace`: Time given to the server at each step before escalating
+    pub async fn shutdown(&self, grace: Duration) -> Result<Option<ExitStatus>, Error> {
+        let process = self.io.process().clone();
+        if let Some(status) = process.exit_status() {
+            return Ok(status);
+        }
+
+        let response = self.send_shutdown(grace);
+        shutdown_sequence(response, self.listener.notifier(), process, grace).await
+    }
+
+    // Move to `ShuttingDown` and send `shutdown`, unless the server was never initialized
+    fn send_shutdown(
+        &self,
+        timeout: Duration,
+    ) -> Option<impl Future<Output = Result<(), Error>> + Send + 'static> {
+        let previous = self
+            .listener
+            .state()
+            .send_replace(ServerState::ShuttingDown);
+
+        (previous != ServerState::Starting).then(|| {
+            self.listener
+                .request_with_timeout::<request::Shutdown>((), Some(timeout))
+                .1
+        })
+    }
+
     /// Kill the tasks
     pub fn kill(&mut self) -> anyhow::Result<()> {
         self.io.kill()?;
@@ -219,3 +1047,56 @@ impl LanguageServer {
         Ok(())
     }
 }
+
+impl Drop for LanguageServer {
+    /// Best effort version of [LanguageServer::shutdown], performed in the background if
+    /// the process is still running and we are inside a tokio runtime
+    fn drop(&mut self) {
+        let process = self.io.process().clone();
+        if process.exit_status().is_some() {
+            return;
+        }
+
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+
+        let response = self.send_shutdown(LSP_SHUTDOWN_TIMEOUT);
+        let notifier = self.listener.notifier();
+
+        runtime.spawn(async move {
+            if let Err(error) =
+                shutdown_sequence(response, notifier, process, LSP_SHUTDOWN_TIMEOUT).await
+            {
+                log::warn!("Failed to shutdown LSP: {}", error);
+            }
+        });
+    }
+}
+
+// Wait for the `shutdown` response then send `exit`, escalating to signals if the process
+// doesn't exit by itself
+async fn shutdown_sequence(
+    response: Option<impl Future<Output = Result<(), Error>>>,
+    notifier: Notifier,
+    process: ProcessHandle,
+    grace: Duration,
+) -> Result<Option<ExitStatus>, Error> {
+    if let Some(response) = response {
+        if let Err(error) = response.await {
+            log::warn!("LSP did not respond to shutdown: {}", error);
+        }
+    }
+
+    if let Err(error) = notifier.notify::<notification::Exit>(()) {
+        log::warn!("Failed to send exit to LSP: {}", error);
+    }
+
+    process
+        .escalate(grace)
+        .await
+        .ok_or_else(|| Error::ServerExited {
+            status: None,
+            stderr: String::new(),
+        })
+}
@@ -0,0 +1,194 @@
+use std::{collections::HashMap, sync::Arc};
+
+use lsp_types::{
+    notification, request, ProgressParams, ProgressParamsValue, ProgressToken, WorkDoneProgress,
+};
+use parking_lot::Mutex;
+
+use crate::{
+    io::ProgressHandler,
+    listener::Listener,
+    stream::{self, LagPolicy, NotificationStream, StreamSender},
+};
+
+/// State of a work done progress reported by the server
+///
+/// * `title`: Title of the operation, eg. "Indexing"
+/// * `message`: Latest message, eg. "3/25 files"
+/// * `percentage`: Latest percentage, from 0 to 100
+/// * `cancellable`: Whether the server accepts `window/workDoneProgress/cancel` for it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Progress {
+    pub title: String,
+    pub message: Option<String>,
+    pub percentage: Option<u32>,
+    pub cancellable: bool,
+}
+
+/// A change of a work done progress
+///
+/// * `Begin`: The progress started
+/// * `Report`: The progress was updated, with its latest state
+/// * `End`: The progress is done, with the final message of the server
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProgressEvent {
+    Begin {
+        token: ProgressToken,
+        progress: Progress,
+    },
+    Report {
+        token: ProgressToken,
+        progress: Progress,
+    },
+    End {
+        token: ProgressToken,
+        message: Option<String>,
+    },
+}
+
+impl ProgressEvent {
+    /// Token of the progress
+    pub fn token(&self) -> &ProgressToken {
+        match self {
+            ProgressEvent::Begin { token, .. }
+            | ProgressEvent::Report { token, .. }
+            | ProgressEvent::End { token, .. } => token,
+        }
+    }
+}
+
+// Keep track of the work done progress of the server
+// `active` is updated before any other `$/progress` handler is called, it is registered first
+pub(crate) struct ProgressTracker {
+    active: Arc<Mutex<HashMap<ProgressToken, Progress>>>,
+}
+
+impl ProgressTracker {
+    pub(crate) fn new(listener: &Listener) -> Self {
+        let active = Arc::new(Mutex::new(HashMap::default()));
+
+        // Tokens are accepted as is, they are tracked once the progress begins
+        listener
+            .on_fallback_request::<request::WorkDoneProgressCreate, _, _, _>(|_| async { Ok(()) })
+            .detach();
+
+        listener
+            .on_notification::<notification::Progress, _>({
+                let active = active.clone();
+                move |params| update(&mut active.lock(), params)
+            })
+            .detach();
+
+        Self { active }
+    }
+
+    pub(crate) fn active(&self) -> HashMap<ProgressToken, Progress> {
+        self.active.lock().clone()
+    }
+
+    pub(crate) fn get(&self, token: &ProgressToken) -> Option<Progress> {
+        self.active.lock().get(token).cloned()
+    }
+
+    pub(crate) fn events(
+        &self,
+        listener: &Listener,
+        capacity: usize,
+        policy: LagPolicy,
+    ) -> NotificationStream<ProgressEvent> {
+        let (sender, stream) = stream::channel(capacity, policy);
+        let active = self.active.clone();
+
+        let subscription = listener.on_notification::<notification::Progress, _>(move |params| {
+            if let Some(event) = event(&active.lock(), params) {
+                sender.send(event)
+            }
+        });
+
+        stream.with_subscription(subscription)
+    }
+}
+
+// Handler of the `$/progress` of a token attached to one of our requests
+// The events of the token are sent to `sender`, the value is passed on so the tracker sees it too
+pub(crate) fn request_handler(
+    token: ProgressToken,
+    sender: StreamSender<ProgressEvent>,
+) -> ProgressHandler {
+    let mut active = HashMap::default();
+
+    Box::new(move |value| {
+        if let Ok(value) = serde_json::from_value::<ProgressParamsValue>(value.clone()) {
+            let params = ProgressParams {
+                token: token.clone(),
+                value,
+            };
+
+            update(&mut active, params.clone());
+            if let Some(event) = event(&active, params) {
+                sender.send(event)
+            }
+        }
+
+        Some(value)
+    })
+}
+
+// Event of a `$/progress` notification, `active` must already be updated with it
+fn event(
+    active: &HashMap<ProgressToken, Progress>,
+    params: ProgressParams,
+) -> Option<ProgressEvent> {
+    let ProgressParamsValue::WorkDone(value) = params.value;
+    let token = params.token;
+
+    match value {
+        WorkDoneProgress::Begin(_) | WorkDoneProgress::Report(_) => {
+            let progress = active.get(&token).cloned()?;
+
+            match value {
+                WorkDoneProgress::Begin(_) => Some(ProgressEvent::Begin { token, progress }),
+                _ => Some(ProgressEvent::Report { token, progress }),
+            }
+        }
+        WorkDoneProgress::End(end) => Some(ProgressEvent::End {
+            token,
+            message: end.message,
+        }),
+    }
+}
+
+fn update(active: &mut HashMap<ProgressToken, Progress>, params: ProgressParams) {
+    let ProgressParamsValue::WorkDone(value) = params.value;
+
+    match value {
+        WorkDoneProgress::Begin(begin) => {
+            active.insert(
+                params.token,
+                Progress {
+                    title: begin.title,
+                    message: begin.message,
+                    percentage: begin.percentage,
+                    cancellable: begin.cancellable.unwrap_or_default(),
+                },
+            );
+        }
+        WorkDoneProgress::Report(report) => {
+            // Reports only carry what changed
+            if let Some(progress) = active.get_mut(&params.token) {
+                if let Some(cancellable) = report.cancellable {
+                    progress.cancellable = cancellable;
+                }
+                if report.message.is_some() {
+                    progress.message = report.message;
+                }
+                if report.percentage.is_some() {
+                    progress.percentage = report.percentage;
+                }
+            }
+        }
+        WorkDoneProgress::End(_) => {
+            active.remove(&params.token);
+        }
+    }
+}
@@ -0,0 +1,588 @@
+use std::sync::Arc;
+
+use lsp_types::{
+    request::{self, Request},
+    DocumentFilter, DocumentSelector, RegistrationParams, ServerCapabilities, UnregistrationParams,
+    Uri,
+};
+use parking_lot::{Mutex, RwLock};
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use tokio::sync::watch;
+
+use crate::{document::DocumentStore, listener::Listener, utils};
+
+// Where the support of a request is declared
+//
+// * `method`: Method of the request
+// * `provider`: Pointer to the static capability in the [ServerCapabilities]
+// * `registration`: Method of the dynamic registration enabling the request
+// * `options`: Pointer to the option enabling the request in the registration options, empty if
+//   the registration alone enables it
+struct Capability {
+    method: &'static str,
+    provider: &'static str,
+    registration: &'static str,
+    options: &'static str,
+}
+
+const fn capability(
+    method: &'static str,
+    provider: &'static str,
+    registration: &'static str,
+    options: &'static str,
+) -> Capability {
+    Capability {
+        method,
+        provider,
+        registration,
+        options,
+    }
+}
+
+// Registration of the semantic tokens requests, no request has this method
+const SEMANTIC_TOKENS: &str = "textDocument/semanticTokens";
+
+// Method of the inline completion request, lsp_types only defines the request with its `proposed`
+// feature
+const INLINE_COMPLETION: &str = "textDocument/inlineCompletion";
+
+// Requests the server must declare support for, requests missing from the table are always
+// supported
+const CAPABILITIES: &[Capability] = &[
+    capability(
+        request::Completion::METHOD,
+        "/completionProvider",
+        request::Completion::METHOD,
+        "",
+    ),
+    capability(
+        request::ResolveCompletionItem::METHOD,
+        "/completionProvider/resolveProvider",
+        request::Completion::METHOD,
+        "/resolveProvider",
+    ),
+    capability(
+        request::HoverRequest::METHOD,
+        "/hoverProvider",
+        request::HoverRequest::METHOD,
+        "",
+    ),
+    capability(
+        request::SignatureHelpRequest::METHOD,
+        "/signatureHelpProvider",
+        request::SignatureHelpRequest::METHOD,
+        "",
+    ),
+    capability(
+        request::GotoDeclaration::METHOD,
+        "/declarationProvider",
+        request::GotoDeclaration::METHOD,
+        "",
+    ),
+    capability(
+        request::GotoDefinition::METHOD,
+        "/definitionProvider",
+        request::GotoDefinition::METHOD,
+        "",
+    ),
+    capability(
+        request::GotoTypeDefinition::METHOD,
+        "/typeDefinitionProvider",
+        request::GotoTypeDefinition::METHOD,
+        "",
+    ),
+    capability(
+        request::GotoImplementation::METHOD,
+        "/implementationProvider",
+        request::GotoImplementation::METHOD,
+        "",
+    ),
+    capability(
+        request::References::METHOD,
+        "/referencesProvider",
+        request::References::METHOD,
+        "",
+    ),
+    capability(
+        request::DocumentHighlightRequest::METHOD,
+        "/documentHighlightProvider",
+        request::DocumentHighlightRequest::METHOD,
+        "",
+    ),
+    capability(
+        request::DocumentSymbolRequest::METHOD,
+        "/documentSymbolProvider",
+        request::DocumentSymbolRequest::METHOD,
+        "",
+    ),
+    capability(
+        request::CodeActionRequest::METHOD,
+        "/codeActionProvider",
+        request::CodeActionRequest::METHOD,
+        "",
+    ),
+    capability(
+        request::CodeActionResolveRequest::METHOD,
+        "/codeActionProvider/resolveProvider",
+        request::CodeActionRequest::METHOD,
+        "/resolveProvider",
+    ),
+    capability(
+        request::CodeLensRequest::METHOD,
+        "/codeLensProvider",
+        request::CodeLensRequest::METHOD,
+        "",
+    ),
+    capability(
+        request::CodeLensResolve::METHOD,
+        "/codeLensProvider/resolveProvider",
+        request::CodeLensRequest::METHOD,
+        "/resolveProvider",
+    ),
+    capability(
+        request::DocumentLinkRequest::METHOD,
+        "/documentLinkProvider",
+        request::DocumentLinkRequest::METHOD,
+        "",
+    ),
+    capability(
+        request::DocumentLinkResolve::METHOD,
+        "/documentLinkProvider/resolveProvider",
+        request::DocumentLinkRequest::METHOD,
+        "/resolveProvider",
+    ),
+    capability(
+        request::DocumentColor::METHOD,
+        "/colorProvider",
+        request::DocumentColor::METHOD,
+        "",
+    ),
+    capability(
+        request::ColorPresentationRequest::METHOD,
+        "/colorProvider",
+        request::DocumentColor::METHOD,
+        "",
+    ),
+    capability(
+        request::Formatting::METHOD,
+        "/documentFormattingProvider",
+        request::Formatting::METHOD,
+        "",
+    ),
+    capability(
+        request::RangeFormatting::METHOD,
+        "/documentRangeFormattingProvider",
+        request::RangeFormatting::METHOD,
+        "",
+    ),
+    capability(
+        request::OnTypeFormatting::METHOD,
+        "/documentOnTypeFormattingProvider",
+        request::OnTypeFormatting::METHOD,
+        "",
+    ),
+    capability(
+        request::Rename::METHOD,
+        "/renameProvider",
+        request::Rename::METHOD,
+        "",
+    ),
+    capability(
+        request::PrepareRenameRequest::METHOD,
+        "/renameProvider/prepareProvider",
+        request::Rename::METHOD,
+        "/prepareProvider",
+    ),
+    capability(
+        request::FoldingRangeRequest::METHOD,
+        "/foldingRangeProvider",
+        request::FoldingRangeRequest::METHOD,
+        "",
+    ),
+    capability(
+        request::SelectionRangeRequest::METHOD,
+        "/selectionRangeProvider",
+        request::SelectionRangeRequest::METHOD,
+        "",
+    ),
+    capability(
+        request::CallHierarchyPrepare::METHOD,
+        "/callHierarchyProvider",
+        request::CallHierarchyPrepare::METHOD,
+        "",
+    ),
+    capability(
+        request::CallHierarchyIncomingCalls::METHOD,
+        "/callHierarchyProvider",
+        request::CallHierarchyPrepare::METHOD,
+        "",
+    ),
+    capability(
+        request::CallHierarchyOutgoingCalls::METHOD,
+        "/callHierarchyProvider",
+        request::CallHierarchyPrepare::METHOD,
+        "",
+    ),
+    capability(
+        request::SemanticTokensFullRequest::METHOD,
+        "/semanticTokensProvider/full",
+        SEMANTIC_TOKENS,
+        "/full",
+    ),
+    capability(
+        request::SemanticTokensFullDeltaRequest::METHOD,
+        "/semanticTokensProvider/full/delta",
+        SEMANTIC_TOKENS,
+        "/full/delta",
+    ),
+    capability(
+        request::SemanticTokensRangeRequest::METHOD,
+        "/semanticTokensProvider/range",
+        SEMANTIC_TOKENS,
+        "/range",
+    ),
+    capability(
+        request::LinkedEditingRange::METHOD,
+        "/linkedEditingRangeProvider",
+        request::LinkedEditingRange::METHOD,
+        "",
+    ),
+    capability(
+        request::MonikerRequest::METHOD,
+        "/monikerProvider",
+        request::MonikerRequest::METHOD,
+        "",
+    ),
+    capability(
+        request::InlineValueRequest::METHOD,
+        "/inlineValueProvider",
+        request::InlineValueRequest::METHOD,
+        "",
+    ),
+    capability(
+        request::InlayHintRequest::METHOD,
+        "/inlayHintProvider",
+        request::InlayHintRequest::METHOD,
+        "",
+    ),
+    capability(
+        request::InlayHintResolveRequest::METHOD,
+        "/inlayHintProvider/resolveProvider",
+        request::InlayHintRequest::METHOD,
+        "/resolveProvider",
+    ),
+    capability(
+        INLINE_COMPLETION,
+        "/inlineCompletionProvider",
+        INLINE_COMPLETION,
+        "",
+    ),
+    capability(
+        request::DocumentDiagnosticRequest::METHOD,
+        "/diagnosticProvider",
+        request::DocumentDiagnosticRequest::METHOD,
+        "",
+    ),
+    capability(
+        request::WorkspaceDiagnosticRequest::METHOD,
+        "/diagnosticProvider/workspaceDiagnostics",
+        request::DocumentDiagnosticRequest::METHOD,
+        "/workspaceDiagnostics",
+    ),
+    capability(
+        request::WillSaveWaitUntil::METHOD,
+        "/textDocumentSync/willSaveWaitUntil",
+        request::WillSaveWaitUntil::METHOD,
+        "",
+    ),
+    capability(
+        request::WorkspaceSymbolRequest::METHOD,
+        "/workspaceSymbolProvider",
+        request::WorkspaceSymbolRequest::METHOD,
+        "",
+    ),
+    capability(
+        request::WorkspaceSymbolResolve::METHOD,
+        "/workspaceSymbolProvider/resolveProvider",
+        request::WorkspaceSymbolRequest::METHOD,
+        "/resolveProvider",
+    ),
+    capability(
+        request::ExecuteCommand::METHOD,
+        "/executeCommandProvider",
+        request::ExecuteCommand::METHOD,
+        "",
+    ),
+    capability(
+        request::WillCreateFiles::METHOD,
+        "/workspace/fileOperations/willCreate",
+        request::WillCreateFiles::METHOD,
+        "",
+    ),
+    capability(
+        request::WillRenameFiles::METHOD,
+        "/workspace/fileOperations/willRename",
+        request::WillRenameFiles::METHOD,
+        "",
+    ),
+    capability(
+        request::WillDeleteFiles::METHOD,
+        "/workspace/fileOperations/willDelete",
+        request::WillDeleteFiles::METHOD,
+        "",
+    ),
+];
+
+fn find_capability(method: &str) -> Option<&'static Capability> {
+    CAPABILITIES
+        .iter()
+        .find(|capability| capability.method == method)
+}
+
+// Whether the value enables a feature: present, and not `false`
+fn enabled(value: Option<&Value>) -> bool {
+    !matches!(value, None | Some(Value::Null) | Some(Value::Bool(false)))
+}
+
+/// A capability registered by the server with `client/registerCapability`
+///
+/// * `id`: Id of the registration, used by the server to unregister it
+/// * `method`: Method of the registered feature
+/// * `register_options`: Options of the feature, their type depends on the method
+/// * `document_selector`: Documents the registration applies to, `None` for every document
+#[derive(Debug, Clone, PartialEq)]
+pub struct DynamicRegistration {
+    pub id: String,
+    pub method: String,
+    pub register_options: Option<Value>,
+    pub document_selector: Option<DocumentSelector>,
+}
+
+impl DynamicRegistration {
+    /// The registration options as the type of the method, e.g.
+    /// [lsp_types::DidChangeWatchedFilesRegistrationOptions] for
+    /// `workspace/didChangeWatchedFiles`
+    pub fn options<T: DeserializeOwned>(&self) -> Option<T> {
+        serde_json::from_value(self.register_options.clone()?).ok()
+    }
+
+    /// Whether the registration applies to a document
+    ///
+    /// * `uri`: Uri of the document
+    /// * `language_id`: Language of the document, filters on the language never match without it
+    pub fn applies_to(&self, uri: &Uri, language_id: Option<&str>) -> bool {
+        self.document_selector
+            .as_ref()
+            .is_none_or(|selector| selector_matches(selector, uri, language_id))
+    }
+}
+
+// Whether one of the filters matches the document
+fn selector_matches(selector: &DocumentSelector, uri: &Uri, language_id: Option<&str>) -> bool {
+    selector
+        .iter()
+        .any(|filter| filter_matches(filter, uri, language_id))
+}
+
+// The language of a document which wasn't opened through the store is unknown, it matches
+fn filter_matches(filter: &DocumentFilter, uri: &Uri, language_id: Option<&str>) -> bool {
+    let language = match (filter.language.as_deref(), language_id) {
+        (Some(language), Some(language_id)) => language == language_id,
+        _ => true,
+    };
+    let scheme = filter.scheme.as_deref().is_none_or(|scheme| {
+        uri.scheme()
+            .is_some_and(|uri_scheme| uri_scheme.as_str().eq_ignore_ascii_case(scheme))
+    });
+    let pattern = filter
+        .pattern
+        .as_deref()
+        .is_none_or(|pattern| utils::glob_match(pattern, &utils::uri_path(uri)));
+
+    language && scheme && pattern
+}
+
+// Document selector of static or dynamic options, `None` if they apply to every document
+fn document_selector(options: Option<&Value>) -> Option<DocumentSelector> {
+    serde_json::from_value(options?.get("documentSelector")?.clone()).ok()
+}
+
+/// The capabilities registered by the server with `client/registerCapability`, and not
+/// unregistered yet with `client/unregisterCapability`
+/// Both requests are answered from the start of the server, register a handler with
+/// [crate::process::LanguageServer::on_request] or a default reply with
+/// [crate::process::LanguageServer::set_default_reply] to override it, the registrations are
+/// no longer tracked then. Servers only register
+/// capabilities the client declared `dynamicRegistration` for in the `initialize` request
+///
+/// See [crate::process::LanguageServer::supports] to know whether a request is supported, from
+/// the static and dynamic capabilities
+///
+/// # Usage
+/// ```rust
+///     use chan_rs::lsp_types::DidChangeWatchedFilesRegistrationOptions;
+///
+///     for registration in server.registrations().by_method("workspace/didChangeWatchedFiles") {
+///         let options = registration.options::<DidChangeWatchedFilesRegistrationOptions>();
+///     }
+/// ```
+#[derive(Clone)]
+pub struct CapabilityRegistry {
+    registrations: Arc<Mutex<Vec<DynamicRegistration>>>,
+    changes: Arc<watch::Sender<()>>,
+    // Static capabilities of the server, serialized once when they change
+    capabilities: Arc<RwLock<Value>>,
+}
+
+impl CapabilityRegistry {
+    pub(crate) fn new(listener: &Listener) -> Self {
+        let registry = Self {
+            registrations: Default::default(),
+            changes: Arc::new(watch::Sender::new(())),
+            capabilities: Default::default(),
+        };
+
+        listener
+            .on_fallback_request::<request::RegisterCapability, _, _, _>({
+                let registry = registry.clone();
+                move |params: RegistrationParams| {
+                    registry.register(params);
+                    async { Ok(()) }
+                }
+            })
+            .detach();
+
+        listener
+            .on_fallback_request::<request::UnregisterCapability, _, _, _>({
+                let registry = registry.clone();
+                move |params: UnregistrationParams| {
+                    registry.unregister(params);
+                    async { Ok(()) }
+                }
+            })
+            .detach();
+
+        registry
+    }
+
+    fn register(&self, params: RegistrationParams) {
+        let mut registrations = self.registrations.lock();
+
+        for registration in params.registrations {
+            // A registration registered again replaces the previous one
+            registrations.retain(|registered| registered.id != registration.id);
+            registrations.push(DynamicRegistration {
+                document_selector: document_selector(registration.register_options.as_ref()),
+                id: registration.id,
+                method: registration.method,
+                register_options: registration.register_options,
+            });
+        }
+
+        self.changes.send_replace(());
+    }
+
+    fn unregister(&self, params: UnregistrationParams) {
+        let mut registrations = self.registrations.lock();
+
+        for unregistration in params.unregisterations {
+            registrations.retain(|registered| {
+                registered.id != unregistration.id || registered.method != unregistration.method
+            });
+        }
+
+        self.changes.send_replace(());
+    }
+
+    // Notified every time the registrations change
+    #[cfg(all(feature = "watcher", target_os = "linux"))]
+    pub(crate) fn subscribe(&self) -> watch::Receiver<()> {
+        self.changes.subscribe()
+    }
+
+    /// Every registration, in the order they were registered
+    pub fn registrations(&self) -> Vec<DynamicRegistration> {
+        self.registrations.lock().clone()
+    }
+
+    /// A registration by id
+    pub fn get(&self, id: &str) -> Option<DynamicRegistration> {
+        self.registrations
+            .lock()
+            .iter()
+            .find(|registration| registration.id == id)
+            .cloned()
+    }
+
+    /// The registrations of a method
+    pub fn by_method(&self, method: &str) -> Vec<DynamicRegistration> {
+        self.registrations
+            .lock()
+            .iter()
+            .filter(|registration| registration.method == method)
+            .cloned()
+            .collect()
+    }
+
+    // Keep the static capabilities of the server, every time they change
+    pub(crate) fn set_capabilities(&self, capabilities: &ServerCapabilities) {
+        *self.capabilities.write() = serde_json::to_value(capabilities).unwrap_or_default();
+    }
+
+    // Whether the support of the method is declared by a capability, the others are always
+    // supported
+    pub(crate) fn declares(method: &str) -> bool {
+        find_capability(method).is_some()
+    }
+
+    /// Whether a request is supported, by the static capabilities or a registration
+    /// Requests which support isn't declared by a capability are always supported.
+    /// Without a document, the document selectors are ignored
+    ///
+    /// * `method`: Method of the request
+    /// * `uri`: Document the request is about
+    /// * `documents`: Open documents, for the language of the document
+    pub(crate) fn supports(
+        &self,
+        method: &str,
+        uri: Option<&Uri>,
+        documents: &DocumentStore,
+    ) -> bool {
+        let Some(capability) = find_capability(method) else {
+            return true;
+        };
+
+        let language_id = uri.and_then(|uri| documents.language_id(uri));
+        let applies = |selector: Option<DocumentSelector>| match (selector, uri) {
+            (Some(selector), Some(uri)) => selector_matches(&selector, uri, language_id.as_deref()),
+            _ => true,
+        };
+
+        // Static options may be registration options, restricted to some documents
+        let capabilities = self.capabilities.read();
+        let provider = capabilities.pointer(capability.provider);
+        if enabled(provider) {
+            let options = capability
+                .provider
+                .split('/')
+                .nth(1)
+                .and_then(|provider| capabilities.get(provider));
+            if applies(document_selector(options)) {
+                return true;
+            }
+        }
+
+        self.registrations.lock().iter().any(|registration| {
+            registration.method == capability.registration
+                && (capability.options.is_empty()
+                    || enabled(
+                        registration
+                            .register_options
+                            .as_ref()
+                            .and_then(|options| options.pointer(capability.options)),
+                    ))
+                && applies(registration.document_selector.clone())
+        })
+    }
+}
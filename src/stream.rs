@@ -0,0 +1,257 @@
+use std::{
+    collections::VecDeque,
+    future::Future,
+    marker::PhantomData,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll, Waker},
+};
+
+use futures::Stream;
+use parking_lot::Mutex;
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+
+use crate::{utils::Subscription, Error};
+
+/// What to do with a new item when the buffer of a stream is full, because the consumer can't
+/// keep up with the server
+///
+/// * `DropOldest`: Drop the oldest buffered item to make room for the new one
+/// * `DropNewest`: Keep the buffer as is and drop the new item
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LagPolicy {
+    #[default]
+    DropOldest,
+    DropNewest,
+}
+
+// Buffer shared by the sending handler and the stream
+struct Shared<T> {
+    buffer: VecDeque<T>,
+    capacity: usize,
+    policy: LagPolicy,
+    lagged: usize,
+    waker: Option<Waker>,
+    closed: bool,
+}
+
+// Sending half, owned by the handler. The stream ends when it is dropped
+pub(crate) struct StreamSender<T> {
+    shared: Arc<Mutex<Shared<T>>>,
+}
+
+impl<T> StreamSender<T> {
+    pub(crate) fn send(&self, item: T) {
+        let mut shared = self.shared.lock();
+
+        if shared.buffer.len() >= shared.capacity {
+            shared.lagged += 1;
+            match shared.policy {
+                LagPolicy::DropOldest => {
+                    shared.buffer.pop_front();
+                }
+                LagPolicy::DropNewest => return,
+            }
+        }
+
+        shared.buffer.push_back(item);
+        if let Some(waker) = shared.waker.take() {
+            waker.wake()
+        }
+    }
+
+    // Whether the stream was dropped
+    pub(crate) fn is_closed(&self) -> bool {
+        Arc::strong_count(&self.shared) == 1
+    }
+}
+
+impl<T> Drop for StreamSender<T> {
+    fn drop(&mut self) {
+        let mut shared = self.shared.lock();
+        shared.closed = true;
+        if let Some(waker) = shared.waker.take() {
+            waker.wake()
+        }
+    }
+}
+
+/// A bounded stream of the messages received from the server
+/// The handler feeding the stream is removed when the stream is dropped
+///
+/// # Usage
+/// ```rust
+///     use futures::StreamExt;
+///     use chan_rs::lsp_types::notification::PublishDiagnostics;
+///
+///     let mut diagnostics = server.notifications::<PublishDiagnostics>();
+///     while let Some(params) = diagnostics.next().await {
+///         ...
+///     }
+/// ```
+pub struct NotificationStream<T> {
+    shared: Arc<Mutex<Shared<T>>>,
+    subscription: Option<Subscription>,
+}
+
+impl<T> NotificationStream<T> {
+    // Attach the subscription of the handler feeding the stream
+    pub(crate) fn with_subscription(mut self, subscription: Subscription) -> Self {
+        self.subscription = Some(subscription);
+        self
+    }
+
+    /// Number of items dropped so far because the buffer was full
+    pub fn lagged(&self) -> usize {
+        self.shared.lock().lagged
+    }
+}
+
+impl<T> Stream for NotificationStream<T> {
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut shared = self.shared.lock();
+
+        if let Some(item) = shared.buffer.pop_front() {
+            return Poll::Ready(Some(item));
+        }
+
+        if shared.closed {
+            return Poll::Ready(None);
+        }
+
+        shared.waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.shared.lock().buffer.len(), None)
+    }
+}
+
+// Create a stream holding at most `capacity` items, and its sending half
+pub(crate) fn channel<T>(
+    capacity: usize,
+    policy: LagPolicy,
+) -> (StreamSender<T>, NotificationStream<T>) {
+    let shared = Arc::new(Mutex::new(Shared {
+        buffer: VecDeque::new(),
+        capacity: capacity.max(1),
+        policy,
+        lagged: 0,
+        waker: None,
+        closed: false,
+    }));
+
+    (
+        StreamSender {
+            shared: shared.clone(),
+        },
+        NotificationStream {
+            shared,
+            subscription: None,
+        },
+    )
+}
+
+/// An item of a [PartialResultStream]
+///
+/// * `Partial`: A batch of results reported by the server before the response
+/// * `Done`: The final response, merged with the batches when the result is an array
+#[derive(Debug, Clone, PartialEq)]
+pub enum PartialResult<T> {
+    Partial(T),
+    Done(T),
+}
+
+// Raw final response of a streamed request
+type ResponseFuture = Pin<Box<dyn Future<Output = Result<Value, Error>> + Send>>;
+
+/// The results of a request as they arrive, see
+/// [crate::process::LanguageServer::request_streaming]
+/// The stream ends after the final response or the first error. Dropping it cancels the request
+pub struct PartialResultStream<T> {
+    partials: NotificationStream<Value>,
+    response: Option<ResponseFuture>,
+    batches: Vec<Value>,
+    _result: PhantomData<fn() -> T>,
+}
+
+impl<T> PartialResultStream<T> {
+    pub(crate) fn new(
+        partials: NotificationStream<Value>,
+        response: impl Future<Output = Result<Value, Error>> + Send + 'static,
+    ) -> Self {
+        Self {
+            partials,
+            response: Some(Box::pin(response)),
+            batches: Vec::new(),
+            _result: PhantomData,
+        }
+    }
+}
+
+impl<T: DeserializeOwned> Stream for PartialResultStream<T> {
+    type Item = Result<PartialResult<T>, Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        let Some(response) = this.response.as_mut() else {
+            return Poll::Ready(None);
+        };
+
+        // Batches are always received before the response, drain them first
+        if let Poll::Ready(Some(batch)) = Pin::new(&mut this.partials).poll_next(cx) {
+            let partial = serde_json::from_value(batch.clone()).map_err(Error::from);
+            this.batches.push(batch);
+            return Poll::Ready(Some(partial.map(PartialResult::Partial)));
+        }
+
+        let response = match response.as_mut().poll(cx) {
+            Poll::Ready(response) => response,
+            Poll::Pending => return Poll::Pending,
+        };
+        this.response = None;
+
+        let batches = std::mem::take(&mut this.batches);
+        Poll::Ready(Some(response.and_then(|response| {
+            serde_json::from_value(merge(batches, response))
+                .map(PartialResult::Done)
+                .map_err(Error::from)
+        })))
+    }
+}
+
+// Merge the batches into the final response
+// Arrays are concatenated, and so are the array fields of objects, eg. `data` of semantic tokens.
+// Otherwise the response wins, unless it is null
+fn merge(batches: Vec<Value>, response: Value) -> Value {
+    batches
+        .into_iter()
+        .rev()
+        .fold(response, |response, batch| match (batch, response) {
+            (Value::Array(mut batch), Value::Array(response)) => {
+                batch.extend(response);
+                Value::Array(batch)
+            }
+            (batch, Value::Null) => batch,
+            (Value::Object(batch), Value::Object(mut response)) => {
+                for (key, value) in batch {
+                    match (value, response.get_mut(&key)) {
+                        (Value::Array(mut value), Some(Value::Array(field))) => {
+                            value.append(field);
+                            *field = value;
+                        }
+                        (value, None) => {
+                            response.insert(key, value);
+                        }
+                        _ => {}
+                    }
+                }
+                Value::Object(response)
+            }
+            (_, response) => response,
+        })
+}
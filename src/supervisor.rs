@@ -0,0 +1,413 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    future::Future,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicI32, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use lsp_types::{
+    notification::{self, Notification},
+    request, CodeActionKind, DidChangeTextDocumentParams, DidCloseTextDocumentParams,
+    DidOpenTextDocumentParams, InitializeParams, TextDocumentItem, Uri,
+};
+use parking_lot::{Mutex, RwLock};
+use serde::Serialize;
+use tokio::task::JoinHandle;
+
+use crate::{
+    process::{LanguageServer, LanguageServerBinary, ServerState},
+    stream::{self, LagPolicy, NotificationStream},
+    utils::{self, Subscription},
+    Error, IOKind, NOTIFICATION_STREAM_CAPACITY,
+};
+
+/// A handler registered through the supervisor, registered again on every restart
+///
+/// * `register`: Register the handler to a server
+/// * `subscription`: Subscription of the handler on the current server
+pub struct Registration {
+    register: Box<dyn Send + Fn(&LanguageServer) -> Subscription>,
+    subscription: Subscription,
+}
+
+/// When and how often a crashed server is restarted
+///
+/// * `initial_backoff`: Delay before the first restart
+/// * `max_backoff`: Upper bound of the delay, it doubles on every restart within the window
+/// * `max_restarts`: Number of restarts allowed within the window before giving up
+/// * `window`: Period over which restarts are counted
+#[derive(Debug, Clone)]
+pub struct RestartPolicy {
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    pub max_restarts: usize,
+    pub window: Duration,
+}
+
+impl Default for RestartPolicy {
+    fn default() -> Self {
+        Self {
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+            max_restarts: 5,
+            window: Duration::from_secs(180),
+        }
+    }
+}
+
+struct Supervisor {
+    binary: LanguageServerBinary,
+    id: i32,
+    root_path: PathBuf,
+    capture: Arc<Mutex<Option<String>>>,
+    code_action_kind: Option<Vec<CodeActionKind>>,
+    params: InitializeParams,
+    policy: RestartPolicy,
+    server: RwLock<Arc<LanguageServer>>,
+    next_id: AtomicI32,
+    registrations: Arc<Mutex<HashMap<i32, Registration>>>,
+    documents: Mutex<HashMap<Uri, TextDocumentItem>>,
+    restarts: AtomicUsize,
+}
+
+/// A language server restarted when it crashes
+/// The initialize handshake is performed again, handlers registered through the supervisor are
+/// registered again, and the documents opened through it or through
+/// [LanguageServer::documents] are opened again
+pub struct SupervisedLanguageServer {
+    supervisor: Arc<Supervisor>,
+    monitor_task: JoinHandle<()>,
+}
+
+impl SupervisedLanguageServer {
+    /// Start the language server, perform the initialize handshake and watch for crashes
+    ///
+    /// # Usage
+    /// ``` rust
+    ///     let server = SupervisedLanguageServer::new(
+    ///         binary,
+    ///         1,
+    ///         root_path,
+    ///         stderr_capture,
+    ///         None,
+    ///         InitializeParams::default(),
+    ///         RestartPolicy::default(),
+    ///     )
+    ///     .await?;
+    /// ```
+    /// * `binary`: See [LanguageServerBinary]
+    /// * `id`: id for the server
+    /// * `root_path`: Root path for the lsp, useful for discovering workspaces
+    /// * `capture`: Stderr capturer
+    /// * `code_action_kind`: List of code action kinds that will be registered during startup
+    /// * `params`: Parameters of the `initialize` request, sent on every start
+    /// * `policy`: See [RestartPolicy]
+    pub async fn new(
+        binary: LanguageServerBinary,
+        id: i32,
+        root_path: &Path,
+        capture: Arc<Mutex<Option<String>>>,
+        code_action_kind: Option<Vec<CodeActionKind>>,
+        params: InitializeParams,
+        policy: RestartPolicy,
+    ) -> anyhow::Result<Self> {
+        let server = LanguageServer::new(
+            binary.clone(),
+            id,
+            root_path,
+            capture.clone(),
+            code_action_kind.clone(),
+        )?;
+        server.initialize(params.clone()).await?;
+
+        let supervisor = Arc::new(Supervisor {
+            binary,
+            id,
+            root_path: root_path.to_path_buf(),
+            capture,
+            code_action_kind,
+            params,
+            policy,
+            server: RwLock::new(Arc::new(server)),
+            next_id: Default::default(),
+            registrations: Default::default(),
+            documents: Default::default(),
+            restarts: Default::default(),
+        });
+
+        let monitor_task = tokio::spawn(Supervisor::monitor(supervisor.clone()));
+
+        Ok(Self {
+            supervisor,
+            monitor_task,
+        })
+    }
+
+    /// The server currently running, it is replaced on restart
+    pub fn server(&self) -> Arc<LanguageServer> {
+        self.supervisor.server.read().clone()
+    }
+
+    /// Number of times the server had been restarted
+    pub fn restart_count(&self) -> usize {
+        self.supervisor.restarts.load(Ordering::SeqCst)
+    }
+
+    /// Send a request to the current server, see [LanguageServer::request]
+    pub async fn request<T: request::Request>(
+        &self,
+        params: T::Params,
+    ) -> Result<T::Result, Error> {
+        self.server().request::<T>(params).await
+    }
+
+    /// Send a notification to the current server, see [LanguageServer::notify]
+    /// `didOpen`, `didChange` and `didClose` are tracked to open the documents again on restart
+    pub async fn notify<T: notification::Notification>(
+        &self,
+        params: T::Params,
+    ) -> Result<(), Error> {
+        if matches!(
+            T::METHOD,
+            notification::DidOpenTextDocument::METHOD
+                | notification::DidChangeTextDocument::METHOD
+                | notification::DidCloseTextDocument::METHOD
+        ) {
+            self.supervisor
+                .track_document(T::METHOD, serde_json::to_value(&params)?);
+        }
+
+        self.server().notify::<T>(params).await
+    }
+
+    /// Register a request handler, see [LanguageServer::on_request]
+    pub fn on_request<T: request::Request, F, Fut, Res>(&self, f: F) -> Subscription
+    where
+        T::Params: 'static + Send,
+        F: Send + 'static + FnMut(T::Params) -> Fut,
+        Fut: Send + 'static + Future<Output = anyhow::Result<Res>>,
+        Res: Serialize,
+    {
+        let f = Arc::new(Mutex::new(f));
+        self.supervisor.register(Box::new(move |server| {
+            let f = f.clone();
+            server.on_request::<T, _, _, _>(move |params| (f.lock())(params))
+        }))
+    }
+
+    /// Register a notification handler, see [LanguageServer::on_notification]
+    pub fn on_notification<T: notification::Notification, F>(&self, f: F) -> Subscription
+    where
+        T::Params: 'static + Send,
+        F: Send + 'static + FnMut(T::Params),
+    {
+        let f = Arc::new(Mutex::new(f));
+        self.supervisor.register(Box::new(move |server| {
+            let f = f.clone();
+            server.on_notification::<T, _>(move |params| (f.lock())(params))
+        }))
+    }
+
+    /// Receive the notifications of a method as a stream, see [LanguageServer::notifications]
+    /// The stream keeps receiving notifications from the restarted servers
+    pub fn notifications<T: notification::Notification>(&self) -> NotificationStream<T::Params>
+    where
+        T::Params: 'static + Send,
+    {
+        self.notifications_with::<T>(NOTIFICATION_STREAM_CAPACITY, LagPolicy::default())
+    }
+
+    /// Receive the notifications of a method as a stream, see
+    /// [LanguageServer::notifications_with]
+    pub fn notifications_with<T: notification::Notification>(
+        &self,
+        capacity: usize,
+        policy: LagPolicy,
+    ) -> NotificationStream<T::Params>
+    where
+        T::Params: 'static + Send,
+    {
+        let (sender, stream) = stream::channel(capacity, policy);
+        let subscription = self.on_notification::<T, _>(move |params| sender.send(params));
+
+        stream.with_subscription(subscription)
+    }
+
+    /// Register a handler to the process's io task, see [LanguageServer::on_io]
+    pub fn on_io<F>(&self, f: F) -> Subscription
+    where
+        F: Send + 'static + FnMut(IOKind, &str),
+    {
+        let f = Arc::new(Mutex::new(f));
+        self.supervisor.register(Box::new(move |server| {
+            let f = f.clone();
+            server.on_io(move |kind, message| (f.lock())(kind, message))
+        }))
+    }
+
+    /// Stop watching the server and shut it down, see [LanguageServer::shutdown]
+    pub async fn shutdown(
+        &self,
+        grace: Duration,
+    ) -> Result<Option<std::process::ExitStatus>, Error> {
+        self.monitor_task.abort();
+        self.server().shutdown(grace).await
+    }
+}
+
+impl Drop for SupervisedLanguageServer {
+    fn drop(&mut self) {
+        self.monitor_task.abort();
+    }
+}
+
+impl Supervisor {
+    fn register(
+        &self,
+        register: Box<dyn Send + Fn(&LanguageServer) -> Subscription>,
+    ) -> Subscription {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let subscription = register(&self.server.read());
+
+        self.registrations.lock().insert(
+            id,
+            Registration {
+                register,
+                subscription,
+            },
+        );
+
+        Subscription::Supervised {
+            id,
+            registrations: Some(Arc::downgrade(&self.registrations)),
+        }
+    }
+
+    fn track_document(&self, method: &str, params: serde_json::Value) {
+        let encoding = self.server.read().position_encoding();
+        let mut documents = self.documents.lock();
+
+        match method {
+            notification::DidOpenTextDocument::METHOD => {
+                if let Ok(params) = serde_json::from_value::<DidOpenTextDocumentParams>(params) {
+                    documents.insert(params.text_document.uri.clone(), params.text_document);
+                }
+            }
+            notification::DidChangeTextDocument::METHOD => {
+                if let Ok(params) = serde_json::from_value::<DidChangeTextDocumentParams>(params) {
+                    if let Some(document) = documents.get_mut(&params.text_document.uri) {
+                        document.version = params.text_document.version;
+                        for change in params.content_changes {
+                            utils::apply_change(&mut document.text, change, &encoding);
+                        }
+                    }
+                }
+            }
+            notification::DidCloseTextDocument::METHOD => {
+                if let Ok(params) = serde_json::from_value::<DidCloseTextDocumentParams>(params) {
+                    documents.remove(&params.text_document.uri);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // Wait for the server to crash and restart it, until the policy gives up
+    async fn monitor(self: Arc<Self>) {
+        let mut restarts = VecDeque::<Instant>::new();
+
+        loop {
+            let mut state = self.server.read().subscribe_state();
+            let state = match state.wait_for(ServerState::is_terminated).await {
+                Ok(state) => *state,
+                Err(_) => return,
+            };
+
+            if let ServerState::Exited(_) = state {
+                return;
+            }
+
+            loop {
+                let now = Instant::now();
+                while restarts
+                    .front()
+                    .is_some_and(|restart| now.duration_since(*restart) > self.policy.window)
+                {
+                    restarts.pop_front();
+                }
+
+                if restarts.len() >= self.policy.max_restarts {
+                    log::error!(
+                        "LSP crashed {} times within {:?}, giving up",
+                        restarts.len(),
+                        self.policy.window
+                    );
+                    return;
+                }
+
+                let backoff = self
+                    .policy
+                    .initial_backoff
+                    .saturating_mul(1 << restarts.len().min(16))
+                    .min(self.policy.max_backoff);
+
+                log::warn!("LSP crashed ({:?}), restarting in {:?}", state, backoff);
+                tokio::time::sleep(backoff).await;
+                restarts.push_back(Instant::now());
+
+                match self.restart().await {
+                    Ok(()) => break,
+                    Err(error) => log::error!("Failed to restart LSP: {}", error),
+                }
+            }
+        }
+    }
+
+    async fn restart(&self) -> anyhow::Result<()> {
+        let server = Arc::new(LanguageServer::new(
+            self.binary.clone(),
+            self.id,
+            &self.root_path,
+            self.capture.clone(),
+            self.code_action_kind.clone(),
+        )?);
+
+        // Handlers first, the server may send requests during the handshake
+        for registration in self.registrations.lock().values_mut() {
+            registration.subscription = (registration.register)(&server);
+        }
+
+        server.initialize(self.params.clone()).await?;
+
+        // The documents of the store, their text includes the changes being held, then the ones
+        // sent by hand
+        let previous = self.server.read().clone();
+        for uri in previous.documents().uris() {
+            if let Some(document) = previous.documents().get(&uri) {
+                server.documents().reopen(document)?;
+            }
+        }
+
+        let documents = self.documents.lock().values().cloned().collect::<Vec<_>>();
+        for text_document in documents {
+            if server.documents().is_open(&text_document.uri) {
+                continue;
+            }
+
+            server
+                .notify::<notification::DidOpenTextDocument>(DidOpenTextDocumentParams {
+                    text_document,
+                })
+                .await?;
+        }
+
+        *self.server.write() = server;
+        self.restarts.fetch_add(1, Ordering::SeqCst);
+
+        Ok(())
+    }
+}
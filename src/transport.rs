@@ -0,0 +1,205 @@
+use std::{path::Path, process::Stdio, sync::Arc};
+
+use anyhow::{anyhow, Context};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpListener, TcpStream},
+    process::{self, Child},
+    select,
+};
+
+use crate::{process::LanguageServerBinary, LSP_CONNECT_TIMEOUT};
+
+// Read half of the connection, the server's output
+pub(crate) type Reader = Box<dyn AsyncRead + Send + Unpin>;
+
+// Write half of the connection, the server's input
+pub(crate) type Writer = Box<dyn AsyncWrite + Send + Unpin>;
+
+/// How to talk to the language server
+///
+/// * `Process`: Spawn the server and talk over its stdio
+/// * `Tcp`: A connected TCP stream
+/// * `Unix`: A connected Unix domain socket
+/// * `Listen`: Spawn the server and wait for it to connect back to the listener, for servers
+///   started with `--port` like arguments. The arguments are passed as is
+/// * `Stream`: Any reader/writer pair, eg. an in-memory duplex
+pub enum Transport {
+    Process(LanguageServerBinary),
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixStream),
+    Listen {
+        binary: LanguageServerBinary,
+        listener: TcpListener,
+    },
+    Stream {
+        reader: Reader,
+        writer: Writer,
+    },
+}
+
+impl Transport {
+    /// Build a transport from any reader/writer pair
+    ///
+    /// # Usage
+    /// ```rust
+    ///     let (client, server) = tokio::io::duplex(1024);
+    ///     let (reader, writer) = tokio::io::split(client);
+    ///     let transport = Transport::stream(reader, writer);
+    /// ```
+    /// * `reader`: Output of the server
+    /// * `writer`: Input of the server
+    pub fn stream(
+        reader: impl AsyncRead + Send + Unpin + 'static,
+        writer: impl AsyncWrite + Send + Unpin + 'static,
+    ) -> Self {
+        Transport::Stream {
+            reader: Box::new(reader),
+            writer: Box::new(writer),
+        }
+    }
+
+    /// Connect to a server listening on a TCP address
+    pub async fn tcp(address: impl tokio::net::ToSocketAddrs) -> anyhow::Result<Self> {
+        let stream = TcpStream::connect(address)
+            .await
+            .context("failed to connect to lsp server")?;
+        Ok(Transport::Tcp(stream))
+    }
+
+    /// Connect to a server listening on a Unix domain socket
+    #[cfg(unix)]
+    pub async fn unix(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let stream = tokio::net::UnixStream::connect(path)
+            .await
+            .context("failed to connect to lsp server")?;
+        Ok(Transport::Unix(stream))
+    }
+
+    // Spawn the server if needed and open the connection to it
+    pub(crate) async fn connect(self, working_dir: &Path) -> anyhow::Result<Connection> {
+        match self {
+            Transport::Process(binary) => Connection::spawn(binary, working_dir),
+            Transport::Tcp(stream) => {
+                let name = stream
+                    .peer_addr()
+                    .map(|address| address.to_string())
+                    .unwrap_or_default();
+                let (reader, writer) = stream.into_split();
+                Ok(Connection::new(reader, writer, name))
+            }
+            #[cfg(unix)]
+            Transport::Unix(stream) => {
+                let name = stream
+                    .peer_addr()
+                    .ok()
+                    .and_then(|address| {
+                        address
+                            .as_pathname()
+                            .map(|path| path.to_string_lossy().into_owned())
+                    })
+                    .unwrap_or_default();
+                let (reader, writer) = stream.into_split();
+                Ok(Connection::new(reader, writer, name))
+            }
+            Transport::Listen { binary, listener } => {
+                let mut process = spawn(&binary, working_dir, false)?;
+
+                let stream = select! {
+                    accepted = listener.accept() => accepted.context("failed to accept lsp server connection")?.0,
+                    status = process.wait() => {
+                        return Err(anyhow!("lsp server exited before connecting: {}", status?));
+                    }
+                    _ = tokio::time::sleep(LSP_CONNECT_TIMEOUT) => {
+                        return Err(anyhow!("lsp server did not connect after {:?}", LSP_CONNECT_TIMEOUT));
+                    }
+                };
+
+                let (reader, writer) = stream.into_split();
+                let mut connection = Connection::new(reader, writer, binary_name(&binary));
+                connection.process = Some(process);
+                Ok(connection)
+            }
+            Transport::Stream { reader, writer } => Ok(Connection {
+                reader,
+                writer,
+                process: None,
+                name: Arc::default(),
+            }),
+        }
+    }
+}
+
+/// An opened connection to the server
+///
+/// * `reader`: Output of the server
+/// * `writer`: Input of the server
+/// * `process`: The server process if we spawned it, its stderr is piped
+/// * `name`: Name of the server
+pub(crate) struct Connection {
+    pub(crate) reader: Reader,
+    pub(crate) writer: Writer,
+    pub(crate) process: Option<Child>,
+    pub(crate) name: Arc<str>,
+}
+
+impl Connection {
+    fn new(
+        reader: impl AsyncRead + Send + Unpin + 'static,
+        writer: impl AsyncWrite + Send + Unpin + 'static,
+        name: impl Into<Arc<str>>,
+    ) -> Self {
+        Self {
+            reader: Box::new(reader),
+            writer: Box::new(writer),
+            process: None,
+            name: name.into(),
+        }
+    }
+
+    // Spawn the server with piped stdio
+    pub(crate) fn spawn(binary: LanguageServerBinary, working_dir: &Path) -> anyhow::Result<Self> {
+        let mut process = spawn(&binary, working_dir, true)?;
+
+        let stdin = process.stdin.take().unwrap();
+        let stdout = process.stdout.take().unwrap();
+
+        let mut connection = Connection::new(stdout, stdin, binary_name(&binary));
+        connection.process = Some(process);
+        Ok(connection)
+    }
+}
+
+// Spawn the server, `stdio` tells whether it talks over stdin and stdout
+fn spawn(binary: &LanguageServerBinary, working_dir: &Path, stdio: bool) -> anyhow::Result<Child> {
+    log::info!(
+        "Starting LSP. Path: {:?}, working directory: {:?}, args: {:?}",
+        binary.path.to_str(),
+        working_dir.to_str(),
+        &binary.args
+    );
+
+    let stdio = || if stdio { Stdio::piped() } else { Stdio::null() };
+
+    let mut command = process::Command::new(&binary.path);
+    command
+        .current_dir(working_dir)
+        .args(&binary.args)
+        .envs(binary.envs.clone().unwrap_or_default())
+        .stdout(stdio())
+        .stdin(stdio())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true);
+
+    command
+        .spawn()
+        .with_context(|| "failed to spawn lsp server".to_string())
+}
+
+fn binary_name(binary: &LanguageServerBinary) -> Arc<str> {
+    match binary.path.file_name() {
+        Some(name) => name.to_string_lossy().into(),
+        None => Arc::default(),
+    }
+}
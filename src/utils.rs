@@ -1,11 +1,17 @@
 use std::{
-    collections::HashMap,
-    sync::{Arc, Weak},
+    collections::{HashMap, HashSet},
+    path::Path,
+    str::FromStr,
+    sync::Weak,
 };
 
+use lsp_types::{PositionEncodingKind, ProgressToken, TextDocumentContentChangeEvent, Uri};
 use parking_lot::Mutex;
+use tokio::task::AbortHandle;
 
-use crate::io::{IoHandler, NotificationHandler};
+use crate::encoding;
+use crate::io::{IoHandler, NotificationHandlers, ProgressHandler};
+use crate::supervisor::Registration;
 
 pub(crate) struct Defered<F: FnOnce()>(Option<F>);
 
@@ -28,27 +34,416 @@ pub fn defer<F: FnOnce()>(f: F) -> Defered<F> {
     Defered(Some(f))
 }
 
+/// Directory the server runs in, the root path itself or its parent if it is a file
+pub(crate) fn working_dir(root_path: &Path) -> &Path {
+    if root_path.is_dir() {
+        root_path
+    } else {
+        root_path.parent().unwrap_or_else(|| Path::new("/"))
+    }
+}
+
+/// Convert an absolute path to a `file://` uri, percent encoding reserved characters
+pub(crate) fn path_to_uri(path: &Path) -> Option<Uri> {
+    let path = path.to_str()?;
+    let mut uri = String::from("file://");
+
+    if !path.starts_with('/') {
+        uri.push('/');
+    }
+
+    for byte in path.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => {
+                uri.push(byte as char)
+            }
+            b'\\' => uri.push('/'),
+            _ => uri.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+
+    Uri::from_str(&uri).ok()
+}
+
+/// Path of the uri, percent decoded
+pub(crate) fn uri_path(uri: &Uri) -> String {
+    let path = uri.path().as_str().as_bytes();
+    let mut bytes = Vec::with_capacity(path.len());
+
+    let mut i = 0;
+    while i < path.len() {
+        let decoded = (path[i] == b'%')
+            .then(|| path.get(i + 1..i + 3))
+            .flatten()
+            .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+
+        match decoded {
+            Some(byte) => {
+                bytes.push(byte);
+                i += 3;
+            }
+            None => {
+                bytes.push(path[i]);
+                i += 1;
+            }
+        }
+    }
+
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+/// Whether the uri is the folder, or a file or folder inside it
+pub(crate) fn uri_contains(folder: &Uri, uri: &Uri) -> bool {
+    let folder = folder.as_str().trim_end_matches('/');
+    uri.as_str()
+        .strip_prefix(folder)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+}
+
+/// Whether the path matches a glob pattern of the specification
+/// `*` and `?` match within a path segment, `**` matches any number of segments, `{a,b}`
+/// matches one of the alternatives and `[a-z]` or `[!a-z]` a char of the range
+pub(crate) fn glob_match(pattern: &str, path: &str) -> bool {
+    let pattern = pattern.chars().collect::<Vec<_>>();
+    let path = path.chars().collect::<Vec<_>>();
+
+    expand_groups(&pattern).iter().any(|pattern| {
+        GlobMatcher {
+            pattern,
+            path: &path,
+            failed: HashSet::new(),
+        }
+        .matches(0, 0)
+    })
+}
+
+// The patterns without `{a,b}` groups, one per combination of alternatives. A `{` without its
+// `}` is a plain char
+fn expand_groups(pattern: &[char]) -> Vec<Vec<char>> {
+    let mut i = 0;
+    while i < pattern.len() {
+        match pattern[i] {
+            // The chars of a class are never a group
+            '[' if pattern[i + 1..].contains(&']') => {
+                i += pattern[i..]
+                    .iter()
+                    .position(|&c| c == ']')
+                    .unwrap_or_default();
+            }
+            '{' => {
+                // Alternatives are split on the commas outside of nested groups
+                let mut depth = 0;
+                let mut alternatives = vec![Vec::new()];
+                let mut end = None;
+                for (j, &c) in pattern.iter().enumerate().skip(i + 1) {
+                    match c {
+                        '{' => depth += 1,
+                        '}' if depth == 0 => {
+                            end = Some(j);
+                            break;
+                        }
+                        '}' => depth -= 1,
+                        ',' if depth == 0 => {
+                            alternatives.push(Vec::new());
+                            continue;
+                        }
+                        _ => {}
+                    }
+                    if let Some(alternative) = alternatives.last_mut() {
+                        alternative.push(c);
+                    }
+                }
+
+                if let Some(end) = end {
+                    return alternatives
+                        .into_iter()
+                        .flat_map(|alternative| {
+                            expand_groups(&[&alternative[..], &pattern[end + 1..]].concat())
+                        })
+                        .map(|expanded| [&pattern[..i], &expanded[..]].concat())
+                        .collect();
+                }
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+
+    vec![pattern.to_vec()]
+}
+
+// Match a pattern without groups against a path. The positions known not to match are
+// remembered, so `*` and `**` never retry them and the match stays polynomial
+//
+// * `failed`: Positions in the pattern and the path which don't match
+struct GlobMatcher<'a> {
+    pattern: &'a [char],
+    path: &'a [char],
+    failed: HashSet<(usize, usize)>,
+}
+
+impl GlobMatcher<'_> {
+    fn matches(&mut self, p: usize, s: usize) -> bool {
+        if self.failed.contains(&(p, s)) {
+            return false;
+        }
+
+        let matched = self.matches_at(p, s);
+        if !matched {
+            self.failed.insert((p, s));
+        }
+        matched
+    }
+
+    fn matches_at(&mut self, p: usize, s: usize) -> bool {
+        let (pattern, path) = (&self.pattern[p..], &self.path[s..]);
+
+        match pattern {
+            [] => path.is_empty(),
+            ['*', '*', rest @ ..] => {
+                // `**/` also matches no segment at all
+                if matches!(rest, ['/', ..]) && self.matches(p + 3, s) {
+                    return true;
+                }
+                (s..=self.path.len()).any(|i| self.matches(p + 2, i))
+            }
+            ['*', ..] => {
+                let segment = path.iter().position(|&c| c == '/').unwrap_or(path.len());
+                (s..=s + segment).any(|i| self.matches(p + 1, i))
+            }
+            ['?', ..] => matches!(path, [c, ..] if *c != '/') && self.matches(p + 1, s + 1),
+            ['[', class @ ..] if class.contains(&']') => {
+                let end = class.iter().position(|&c| c == ']').unwrap_or_default();
+                let class = &class[..end];
+                let (negated, class) = match class {
+                    ['!' | '^', class @ ..] => (true, class),
+                    _ => (false, class),
+                };
+
+                let Some(&c) = path.first() else {
+                    return false;
+                };
+
+                let mut matched = false;
+                let mut i = 0;
+                while i < class.len() {
+                    if i + 2 < class.len() && class[i + 1] == '-' {
+                        matched |= (class[i]..=class[i + 2]).contains(&c);
+                        i += 3;
+                    } else {
+                        matched |= class[i] == c;
+                        i += 1;
+                    }
+                }
+
+                matched != negated && c != '/' && self.matches(p + end + 2, s + 1)
+            }
+            [c, ..] => matches!(path, [first, ..] if first == c) && self.matches(p + 1, s + 1),
+        }
+    }
+}
+
+/// Apply a content change to the text, ranges are in positions of the encoding
+pub(crate) fn apply_change(
+    text: &mut String,
+    change: TextDocumentContentChangeEvent,
+    encoding: &PositionEncodingKind,
+) {
+    match change.range {
+        Some(range) => {
+            let start = encoding::position_to_offset(text, range.start, encoding);
+            let end = encoding::position_to_offset(text, range.end, encoding).max(start);
+            text.replace_range(start..end, &change.text);
+        }
+        None => *text = change.text,
+    }
+}
+
+/// A registered handler, removed when dropped
+/// Call [Subscription::detach] to keep the handler for the lifetime of the server
+///
+/// * `Notification`: Handler of a notification method
+/// * `Request`: Handler of a request method sent by the server
+/// * `Io`: Handler of the io tasks
+/// * `Progress`: Handler of the `$/progress` of a token we created
+/// * `Supervised`: Handler registered through a [crate::supervisor::SupervisedLanguageServer]
+/// * `Task`: Background task, aborted when dropped
+#[must_use = "the handler is removed when the subscription is dropped"]
 pub enum Subscription {
     Notification {
         method: &'static str,
-        notification_handlers: Option<Arc<Mutex<HashMap<&'static str, NotificationHandler>>>>,
+        id: i32,
+        handlers: Option<Weak<Mutex<NotificationHandlers>>>,
+    },
+
+    Request {
+        method: &'static str,
+        id: i32,
+        handlers: Option<Weak<Mutex<NotificationHandlers>>>,
     },
 
     Io {
         id: i32,
         io_handlers: Option<Weak<Mutex<HashMap<i32, IoHandler>>>>,
     },
+
+    Progress {
+        token: ProgressToken,
+        progress_handlers: Option<Weak<Mutex<HashMap<ProgressToken, ProgressHandler>>>>,
+    },
+
+    Supervised {
+        id: i32,
+        registrations: Option<Weak<Mutex<HashMap<i32, Registration>>>>,
+    },
+
+    Task {
+        task: Option<AbortHandle>,
+    },
 }
 
 impl Subscription {
-    // Detach the handler from foreground task
+    /// Keep the handler registered after the subscription is dropped
     pub fn detach(&mut self) {
         match self {
-            Subscription::Notification {
-                notification_handlers,
-                ..
-            } => *notification_handlers = None,
+            Subscription::Notification { handlers, .. }
+            | Subscription::Request { handlers, .. } => *handlers = None,
             Subscription::Io { io_handlers, .. } => *io_handlers = None,
+            Subscription::Progress {
+                progress_handlers, ..
+            } => *progress_handlers = None,
+            Subscription::Supervised { registrations, .. } => *registrations = None,
+            Subscription::Task { task } => *task = None,
+        }
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        match self {
+            Subscription::Notification {
+                method,
+                id,
+                handlers,
+            }
+            | Subscription::Request {
+                method,
+                id,
+                handlers,
+            } => {
+                let Some(handlers) = handlers.take().and_then(|handlers| handlers.upgrade()) else {
+                    return;
+                };
+
+                let mut handlers = handlers.lock();
+                if let Some(method_handlers) = handlers.get_mut(method) {
+                    method_handlers.retain(|(handler_id, _)| handler_id != id);
+                    if method_handlers.is_empty() {
+                        handlers.remove(method);
+                    }
+                }
+            }
+            Subscription::Io { id, io_handlers } => {
+                if let Some(io_handlers) =
+                    io_handlers.take().and_then(|handlers| handlers.upgrade())
+                {
+                    io_handlers.lock().remove(id);
+                }
+            }
+            Subscription::Progress {
+                token,
+                progress_handlers,
+            } => {
+                if let Some(progress_handlers) = progress_handlers
+                    .take()
+                    .and_then(|handlers| handlers.upgrade())
+                {
+                    progress_handlers.lock().remove(token);
+                }
+            }
+            Subscription::Supervised { id, registrations } => {
+                if let Some(registrations) = registrations
+                    .take()
+                    .and_then(|registrations| registrations.upgrade())
+                {
+                    // Drop the registration outside of the lock, it holds the server's subscription
+                    let registration = registrations.lock().remove(id);
+                    drop(registration);
+                }
+            }
+            Subscription::Task { task } => {
+                if let Some(task) = task.take() {
+                    task.abort();
+                }
+            }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::glob_match;
+
+    #[test]
+    fn star_stays_in_its_segment() {
+        assert!(glob_match("*.rs", "lib.rs"));
+        assert!(glob_match("src/*.rs", "src/lib.rs"));
+        assert!(!glob_match("*.rs", "src/lib.rs"));
+        assert!(!glob_match("src/*.rs", "src/a/lib.rs"));
+        assert!(!glob_match("*.rs", "lib.rsx"));
+    }
+
+    #[test]
+    fn globstar_matches_any_number_of_segments() {
+        assert!(glob_match("**/*.rs", "lib.rs"));
+        assert!(glob_match("**/*.rs", "src/lib.rs"));
+        assert!(glob_match("**/*.rs", "src/a/b/lib.rs"));
+        assert!(glob_match("src/**/lib.rs", "src/lib.rs"));
+        assert!(glob_match("src/**/lib.rs", "src/a/b/lib.rs"));
+        assert!(glob_match("src/**", "src/a/b"));
+        assert!(!glob_match("**/*.rs", "src/lib.rs.bak"));
+        assert!(!glob_match("src/**/lib.rs", "tests/lib.rs"));
+    }
+
+    #[test]
+    fn question_mark_matches_one_char_of_a_segment() {
+        assert!(glob_match("a?c", "abc"));
+        assert!(glob_match("a?c", "aéc"));
+        assert!(!glob_match("a?c", "ac"));
+        assert!(!glob_match("a?c", "abbc"));
+        assert!(!glob_match("a?c", "a/c"));
+    }
+
+    #[test]
+    fn classes() {
+        assert!(glob_match("[a-c]x", "bx"));
+        assert!(!glob_match("[a-c]x", "dx"));
+        assert!(glob_match("[!a-z].txt", "1.txt"));
+        assert!(!glob_match("[!a-z].txt", "a.txt"));
+        assert!(!glob_match("a[!a-z]b", "a/b"));
+        assert!(glob_match("[abc", "[abc"));
+    }
+
+    #[test]
+    fn groups() {
+        assert!(glob_match("*.{rs,toml}", "Cargo.toml"));
+        assert!(!glob_match("*.{rs,toml}", "README.md"));
+        assert!(glob_match("*.{rs,{toml,lock}}", "Cargo.lock"));
+        assert!(glob_match("{src,tests}/**/*.rs", "tests/a/b.rs"));
+        assert!(glob_match("{a,b}{c,d}", "bd"));
+        assert!(!glob_match("{a,b}{c,d}", "ba"));
+        assert!(glob_match("{a", "{a"));
+    }
+
+    #[test]
+    fn backtracking_is_bounded() {
+        let path = format!("{}b", "a/".repeat(64));
+        assert!(!glob_match(
+            "**/a/**/a/**/a/**/a/**/a/**/a/**/a/**/c",
+            &path
+        ));
+
+        let segment = format!("{}b", "a".repeat(64));
+        assert!(!glob_match("*a*a*a*a*a*a*a*a*c", &segment));
+    }
+}
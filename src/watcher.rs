@@ -0,0 +1,640 @@
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::CString,
+    fs, io,
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+        unix::ffi::OsStrExt,
+    },
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use lsp_types::{
+    notification::{self, Notification},
+    DidChangeWatchedFilesParams, DidChangeWatchedFilesRegistrationOptions, FileChangeType,
+    FileEvent, GlobPattern, OneOf, WatchKind,
+};
+use tokio::{io::unix::AsyncFd, select, time::Instant};
+
+use crate::{
+    listener::Notifier, registry::CapabilityRegistry, utils, workspace::WorkspaceFolders, Error,
+};
+
+// Events of the watched directories
+const WATCH_MASK: u32 = libc::IN_CREATE
+    | libc::IN_DELETE
+    | libc::IN_MODIFY
+    | libc::IN_MOVED_FROM
+    | libc::IN_MOVED_TO
+    | libc::IN_MOVE_SELF
+    | libc::IN_ONLYDIR;
+
+// Events of the ancestor of a missing root, enough to see the root appear
+const ANCESTOR_MASK: u32 = libc::IN_CREATE | libc::IN_MOVED_TO | libc::IN_ONLYDIR;
+
+// A glob registered by the server, as an absolute pattern
+//
+// * `pattern`: Glob matched against the absolute path of the files
+// * `root`: Directory containing every path the glob can match
+// * `kind`: Changes the server wants to know about
+struct Watcher {
+    pattern: String,
+    root: PathBuf,
+    kind: WatchKind,
+}
+
+impl Watcher {
+    fn matches(&self, path: &str, change: FileChangeType) -> bool {
+        let kind = if change == FileChangeType::CREATED {
+            WatchKind::Create
+        } else if change == FileChangeType::DELETED {
+            WatchKind::Delete
+        } else {
+            WatchKind::Change
+        };
+
+        self.kind.contains(kind) && utils::glob_match(&self.pattern, path)
+    }
+}
+
+// The watchers of every `workspace/didChangeWatchedFiles` registration
+// Relative globs apply to every workspace folder, relative patterns to their base uri
+fn watchers(registry: &CapabilityRegistry, folders: &WorkspaceFolders) -> Vec<Watcher> {
+    let folders = folders.folders();
+
+    registry
+        .by_method(notification::DidChangeWatchedFiles::METHOD)
+        .iter()
+        .filter_map(|registration| {
+            registration.options::<DidChangeWatchedFilesRegistrationOptions>()
+        })
+        .flat_map(|options| options.watchers)
+        .flat_map(|watcher| {
+            let kind = watcher.kind.unwrap_or(WatchKind::all());
+            let patterns = match watcher.glob_pattern {
+                GlobPattern::String(pattern) if pattern.starts_with('/') => vec![pattern],
+                GlobPattern::String(pattern) => folders
+                    .iter()
+                    .map(|folder| join(&utils::uri_path(&folder.uri), &pattern))
+                    .collect(),
+                GlobPattern::Relative(relative) => {
+                    let base = match &relative.base_uri {
+                        OneOf::Left(folder) => &folder.uri,
+                        OneOf::Right(uri) => uri,
+                    };
+                    vec![join(&utils::uri_path(base), &relative.pattern)]
+                }
+            };
+
+            patterns.into_iter().map(move |pattern| Watcher {
+                root: literal_root(&pattern),
+                pattern,
+                kind,
+            })
+        })
+        .collect()
+}
+
+fn join(base: &str, pattern: &str) -> String {
+    format!(
+        "{}/{}",
+        base.trim_end_matches('/'),
+        pattern.trim_start_matches('/')
+    )
+}
+
+// Longest directory of the pattern without glob characters
+fn literal_root(pattern: &str) -> PathBuf {
+    let mut root = PathBuf::from("/");
+    let mut segments = pattern
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .peekable();
+
+    while let Some(segment) = segments.next() {
+        // The last segment is a file name, not a directory
+        if segments.peek().is_none() || segment.contains(['*', '?', '[', '{']) {
+            break;
+        }
+        root.push(segment);
+    }
+
+    root
+}
+
+// Merge a change into the pending change of the same file
+fn merge(previous: FileChangeType, next: FileChangeType) -> Option<FileChangeType> {
+    if previous == FileChangeType::CREATED && next == FileChangeType::DELETED {
+        // The server never saw the file
+        None
+    } else if previous == FileChangeType::CREATED && next == FileChangeType::CHANGED {
+        Some(FileChangeType::CREATED)
+    } else if previous == FileChangeType::DELETED && next == FileChangeType::CREATED {
+        Some(FileChangeType::CHANGED)
+    } else {
+        Some(next)
+    }
+}
+
+// Changes read from inotify
+//
+// * `changes`: Changed files and directories of the trees
+// * `created`: Directories created or moved inside a tree, not watched yet
+#[derive(Default)]
+struct Events {
+    changes: Vec<(PathBuf, FileChangeType)>,
+    created: Vec<PathBuf>,
+}
+
+// An inotify instance watching directory trees
+//
+// * `directories`: Watched directories of the trees
+// * `ancestors`: Closest existing ancestor of the missing roots, to know when they appear
+// * `roots`: Roots of the trees, existing or not
+// * `watched`: Roots which tree is watched
+// * `stale`: A root appeared or disappeared, the roots must be watched again
+struct Inotify {
+    fd: AsyncFd<OwnedFd>,
+    directories: HashMap<i32, PathBuf>,
+    ancestors: HashMap<i32, PathBuf>,
+    roots: HashSet<PathBuf>,
+    watched: HashSet<PathBuf>,
+    stale: bool,
+}
+
+impl Inotify {
+    fn new() -> io::Result<Self> {
+        let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(Self {
+            fd: AsyncFd::new(unsafe { OwnedFd::from_raw_fd(fd) })?,
+            directories: HashMap::new(),
+            ancestors: HashMap::new(),
+            roots: HashSet::new(),
+            watched: HashSet::new(),
+            stale: false,
+        })
+    }
+
+    // Watch the tree of every existing root, and the closest existing ancestor of the others.
+    // Returns the content of the roots which appeared since the previous call
+    async fn watch_roots(&mut self, roots: HashSet<PathBuf>) -> Vec<PathBuf> {
+        self.stale = false;
+
+        let removed = self
+            .watched
+            .iter()
+            .filter(|root| !roots.contains(*root))
+            .cloned()
+            .collect::<Vec<_>>();
+        for root in removed {
+            self.unwatch_tree(&root);
+            self.watched.remove(&root);
+        }
+
+        let (existing, missing) = roots
+            .iter()
+            .filter(|root| !self.watched.contains(*root))
+            .cloned()
+            .partition::<Vec<_>, _>(|root| root.is_dir());
+
+        let ancestors = missing
+            .iter()
+            .filter_map(|root| root.ancestors().skip(1).find(|path| path.is_dir()))
+            .map(Path::to_path_buf)
+            .collect::<HashSet<_>>();
+        self.watch_ancestors(ancestors);
+
+        // The content of a root which appeared is new to the server
+        let (appeared, added) = existing
+            .into_iter()
+            .partition::<Vec<_>, _>(|root| self.roots.contains(root));
+        self.roots = roots;
+        self.watched.extend(appeared.iter().chain(&added).cloned());
+
+        let mut created = appeared.clone();
+        created.extend(self.watch_trees(appeared, true).await);
+        self.watch_trees(added, false).await;
+
+        created
+    }
+
+    // Watch the directories created inside the trees. Returns their content, they may not be
+    // empty by the time they are watched
+    async fn watch_created(&mut self, directories: Vec<PathBuf>) -> Vec<PathBuf> {
+        self.watch_trees(directories, true).await
+    }
+
+    async fn watch_trees(&mut self, roots: Vec<PathBuf>, list: bool) -> Vec<PathBuf> {
+        if roots.is_empty() {
+            return Vec::new();
+        }
+
+        // The walk keeps its own descriptor, it runs to the end even if the watcher stops
+        let fd = match self.fd.get_ref().try_clone() {
+            Ok(fd) => fd,
+            Err(error) => {
+                log::error!("Failed to walk the watched directories: {}", error);
+                return Vec::new();
+            }
+        };
+        let walked = tokio::task::spawn_blocking(move || walk(fd.as_raw_fd(), roots, list)).await;
+
+        match walked {
+            Ok((directories, entries)) => {
+                self.directories.extend(directories);
+                entries
+            }
+            Err(error) => {
+                log::error!("Failed to walk the watched directories: {}", error);
+                Vec::new()
+            }
+        }
+    }
+
+    // Replace the watches of the ancestors
+    fn watch_ancestors(&mut self, ancestors: HashSet<PathBuf>) {
+        let removed = self
+            .ancestors
+            .iter()
+            .filter(|(_, path)| !ancestors.contains(*path))
+            .map(|(wd, _)| *wd)
+            .collect::<Vec<_>>();
+        for wd in removed {
+            self.ancestors.remove(&wd);
+            unsafe { libc::inotify_rm_watch(self.fd.as_raw_fd(), wd) };
+        }
+
+        for ancestor in ancestors {
+            if self.ancestors.values().any(|path| *path == ancestor) {
+                continue;
+            }
+
+            match add_watch(self.fd.as_raw_fd(), &ancestor, ANCESTOR_MASK) {
+                Ok(wd) => {
+                    self.ancestors.insert(wd, ancestor);
+                }
+                Err(error) => log::warn!("Failed to watch {}: {}", ancestor.display(), error),
+            }
+        }
+    }
+
+    // Stop watching a directory and every directory inside it
+    fn unwatch_tree(&mut self, root: &Path) {
+        let fd = self.fd.as_raw_fd();
+        self.directories.retain(|wd, directory| {
+            if !directory.starts_with(root) {
+                return true;
+            }
+
+            unsafe { libc::inotify_rm_watch(fd, *wd) };
+            false
+        });
+    }
+
+    // Wait for the next events. Nothing is read once the future is dropped, it can be cancelled
+    async fn read(&mut self) -> io::Result<Events> {
+        let mut buffer = [0u8; 4096];
+
+        let len = loop {
+            let mut guard = self.fd.readable().await?;
+            match guard.try_io(|fd| {
+                let len =
+                    unsafe { libc::read(fd.as_raw_fd(), buffer.as_mut_ptr().cast(), buffer.len()) };
+                match len {
+                    len if len < 0 => Err(io::Error::last_os_error()),
+                    len => Ok(len as usize),
+                }
+            }) {
+                Ok(result) => break result?,
+                Err(_would_block) => continue,
+            }
+        };
+
+        let mut events = Events::default();
+        let mut offset = 0;
+        while offset + size_of::<libc::inotify_event>() <= len {
+            let event = unsafe {
+                std::ptr::read_unaligned(buffer[offset..].as_ptr().cast::<libc::inotify_event>())
+            };
+            let name_start = offset + size_of::<libc::inotify_event>();
+            offset = name_start + event.len as usize;
+
+            if event.mask & libc::IN_Q_OVERFLOW != 0 {
+                log::warn!("File watcher overflowed, some changes were missed");
+                continue;
+            }
+
+            // The directory is gone, a root or an ancestor being gone changes what to watch
+            if event.mask & libc::IN_IGNORED != 0 {
+                if let Some(directory) = self.directories.remove(&event.wd) {
+                    if self.watched.remove(&directory) {
+                        self.stale = true;
+                    }
+                }
+                self.stale |= self.ancestors.remove(&event.wd).is_some();
+                continue;
+            }
+
+            // The name is padded with null bytes
+            let name = &buffer[name_start..offset.min(len)];
+            let name = &name[..name
+                .iter()
+                .position(|&byte| byte == 0)
+                .unwrap_or(name.len())];
+
+            if let Some(ancestor) = self.ancestors.get(&event.wd) {
+                let path = ancestor.join(std::ffi::OsStr::from_bytes(name));
+                self.stale |= self.roots.iter().any(|root| root.starts_with(&path));
+                continue;
+            }
+
+            let Some(directory) = self.directories.get(&event.wd).cloned() else {
+                continue;
+            };
+
+            // A moved directory is followed by its parent, unless it is a root
+            if event.mask & libc::IN_MOVE_SELF != 0 {
+                if self.watched.remove(&directory) {
+                    self.unwatch_tree(&directory);
+                    self.stale = true;
+                }
+                continue;
+            }
+
+            let path = directory.join(std::ffi::OsStr::from_bytes(name));
+            let is_dir = event.mask & libc::IN_ISDIR != 0;
+
+            let change = if event.mask & (libc::IN_CREATE | libc::IN_MOVED_TO) != 0 {
+                if is_dir {
+                    events.created.push(path.clone());
+                }
+                FileChangeType::CREATED
+            } else if event.mask & (libc::IN_DELETE | libc::IN_MOVED_FROM) != 0 {
+                // The watches of a moved directory would report its old path
+                if is_dir && event.mask & libc::IN_MOVED_FROM != 0 {
+                    self.unwatch_tree(&path);
+                }
+                FileChangeType::DELETED
+            } else {
+                FileChangeType::CHANGED
+            };
+
+            events.changes.push((path, change));
+        }
+
+        Ok(events)
+    }
+}
+
+fn add_watch(fd: RawFd, directory: &Path, mask: u32) -> io::Result<i32> {
+    let path = CString::new(directory.as_os_str().as_bytes())?;
+    let wd = unsafe { libc::inotify_add_watch(fd, path.as_ptr(), mask) };
+    if wd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(wd)
+}
+
+// Watch every directory of the trees, on the blocking pool as big trees take a while to list.
+// Symbolic links are not followed, they could loop
+// Returns the watched directories, and the entries of the trees if `list` asks for them
+fn walk(fd: RawFd, roots: Vec<PathBuf>, list: bool) -> (Vec<(i32, PathBuf)>, Vec<PathBuf>) {
+    let mut watched = Vec::new();
+    let mut entries = Vec::new();
+    let mut directories = roots;
+
+    while let Some(directory) = directories.pop() {
+        match add_watch(fd, &directory, WATCH_MASK) {
+            Ok(wd) => watched.push((wd, directory.clone())),
+            // Every other directory would fail the same way
+            Err(error) if error.raw_os_error() == Some(libc::ENOSPC) => {
+                log::error!(
+                    "Failed to watch {}, the inotify watch limit is reached. \
+                     Raise fs.inotify.max_user_watches to watch every file",
+                    directory.display()
+                );
+                break;
+            }
+            // Removed before we got to it
+            Err(error) if error.kind() == io::ErrorKind::NotFound => continue,
+            Err(error) => {
+                log::warn!("Failed to watch {}: {}", directory.display(), error);
+                continue;
+            }
+        }
+
+        let Ok(read) = fs::read_dir(&directory) else {
+            continue;
+        };
+
+        for entry in read.flatten() {
+            if entry.file_type().is_ok_and(|kind| kind.is_dir()) {
+                directories.push(entry.path());
+            }
+            if list {
+                entries.push(entry.path());
+            }
+        }
+    }
+
+    (watched, entries)
+}
+
+// Changes waiting for the end of the debounce, gathered from the first one so a steady flow
+// can't hold them back
+#[derive(Default)]
+struct Pending {
+    changes: HashMap<PathBuf, FileChangeType>,
+    deadline: Option<Instant>,
+}
+
+impl Pending {
+    fn record(
+        &mut self,
+        paths: impl IntoIterator<Item = PathBuf>,
+        change: FileChangeType,
+        debounce: Duration,
+    ) {
+        for path in paths {
+            let change = match self.changes.remove(&path) {
+                Some(previous) => merge(previous, change),
+                None => Some(change),
+            };
+            if let Some(change) = change {
+                self.changes.insert(path, change);
+            }
+        }
+
+        if self.deadline.is_none() && !self.changes.is_empty() {
+            self.deadline = Some(Instant::now() + debounce);
+        }
+    }
+
+    fn take(&mut self) -> HashMap<PathBuf, FileChangeType> {
+        self.deadline = None;
+        std::mem::take(&mut self.changes)
+    }
+}
+
+// What woke the watcher up
+enum Wake {
+    Resync,
+    Events(io::Result<Events>),
+    Flush,
+    Closed,
+}
+
+// Watch the files registered by the server, and send their changes as batches of
+// `workspace/didChangeWatchedFiles`
+// The watched directories follow the registrations and the workspace folders
+pub(crate) struct FileWatcher {
+    inotify: Inotify,
+    registry: CapabilityRegistry,
+    folders: WorkspaceFolders,
+    notifier: Notifier,
+    debounce: Duration,
+}
+
+impl FileWatcher {
+    pub(crate) fn new(
+        registry: CapabilityRegistry,
+        folders: WorkspaceFolders,
+        notifier: Notifier,
+        debounce: Duration,
+    ) -> io::Result<Self> {
+        Ok(Self {
+            inotify: Inotify::new()?,
+            registry,
+            folders,
+            notifier,
+            debounce,
+        })
+    }
+
+    pub(crate) async fn run(mut self) {
+        let mut registrations = self.registry.subscribe();
+        let mut folders = self.folders.subscribe();
+
+        let (mut watchers, _) = self.resync().await;
+        let mut pending = Pending::default();
+
+        loop {
+            let flush = async {
+                match pending.deadline {
+                    Some(deadline) => tokio::time::sleep_until(deadline).await,
+                    None => std::future::pending().await,
+                }
+            };
+
+            let wake = select! {
+                changed = registrations.changed() => match changed {
+                    Ok(()) => Wake::Resync,
+                    Err(_) => Wake::Closed,
+                },
+                changed = folders.changed() => match changed {
+                    Ok(()) => Wake::Resync,
+                    Err(_) => Wake::Closed,
+                },
+                events = self.inotify.read() => Wake::Events(events),
+                _ = flush => Wake::Flush,
+            };
+
+            match wake {
+                Wake::Resync => {
+                    let created;
+                    (watchers, created) = self.resync().await;
+                    pending.record(created, FileChangeType::CREATED, self.debounce);
+                }
+                Wake::Events(Ok(events)) => {
+                    for (path, change) in events.changes {
+                        pending.record([path], change, self.debounce);
+                    }
+
+                    let created = self.inotify.watch_created(events.created).await;
+                    pending.record(created, FileChangeType::CREATED, self.debounce);
+
+                    if self.inotify.stale {
+                        let roots = self.inotify.roots.clone();
+                        let created = self.inotify.watch_roots(roots).await;
+                        pending.record(created, FileChangeType::CREATED, self.debounce);
+                    }
+                }
+                Wake::Events(Err(error)) => {
+                    log::error!("File watcher stopped: {}", error);
+                    return;
+                }
+                Wake::Flush => {
+                    if let Err(error) = self.send(&watchers, pending.take()) {
+                        log::warn!("File watcher stopped: {}", error);
+                        return;
+                    }
+                }
+                Wake::Closed => return,
+            }
+        }
+    }
+
+    // Watch the roots of the current registrations, returns the content of the roots which
+    // appeared meanwhile
+    async fn resync(&mut self) -> (Vec<Watcher>, Vec<PathBuf>) {
+        let watchers = watchers(&self.registry, &self.folders);
+
+        // Roots inside another root are already watched
+        let roots = watchers
+            .iter()
+            .map(|watcher| watcher.root.clone())
+            .collect::<HashSet<_>>();
+        let roots = roots
+            .iter()
+            .filter(|root| {
+                !roots
+                    .iter()
+                    .any(|other| other != *root && root.starts_with(other))
+            })
+            .cloned()
+            .collect();
+
+        let created = self.inotify.watch_roots(roots).await;
+        (watchers, created)
+    }
+
+    // Send the changes some watcher asked for
+    fn send(
+        &self,
+        watchers: &[Watcher],
+        pending: HashMap<PathBuf, FileChangeType>,
+    ) -> Result<(), Error> {
+        let mut changes = pending
+            .into_iter()
+            .filter(|(path, change)| {
+                let path = path.to_string_lossy();
+                watchers
+                    .iter()
+                    .any(|watcher| watcher.matches(&path, *change))
+            })
+            .filter_map(|(path, change)| Some((utils::path_to_uri(&path)?, change)))
+            .map(|(uri, typ)| FileEvent { uri, typ })
+            .collect::<Vec<_>>();
+
+        if changes.is_empty() {
+            return Ok(());
+        }
+        changes.sort_by(|a, b| a.uri.as_str().cmp(b.uri.as_str()));
+
+        match self
+            .notifier
+            .notify_checked::<notification::DidChangeWatchedFiles>(DidChangeWatchedFilesParams {
+                changes,
+            }) {
+            // The server registers its watchers once initialized
+            Err(Error::ServerNotInitialized) => Ok(()),
+            result => result,
+        }
+    }
+}
@@ -0,0 +1,188 @@
+use std::{path::Path, sync::Arc};
+
+use lsp_types::{
+    notification::{self, Notification},
+    request, DidChangeWorkspaceFoldersParams, OneOf, ServerCapabilities, Uri, WorkspaceFolder,
+    WorkspaceFoldersChangeEvent,
+};
+use parking_lot::{Mutex, RwLock};
+use tokio::sync::watch;
+
+use crate::{
+    listener::{Listener, Notifier},
+    registry::CapabilityRegistry,
+    utils, Error,
+};
+
+// A folder named after the last component of its path
+fn folder_from_path(path: &Path) -> Option<WorkspaceFolder> {
+    Some(WorkspaceFolder {
+        uri: utils::path_to_uri(path)?,
+        name: path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default(),
+    })
+}
+
+/// The workspace folders opened on the server
+/// The folders are sent in the `initialize` request, then every change is sent with
+/// `workspace/didChangeWorkspaceFolders` if the server asked for it, statically or with a
+/// dynamic registration. `workspace/workspaceFolders` is answered from the start of the server,
+/// register a handler with [crate::process::LanguageServer::on_request] or a default reply with
+/// [crate::process::LanguageServer::set_default_reply] to override it
+///
+/// The root path of the server, or its directory when it is a file, is the first folder, unless
+/// the `initialize` request names its own folders
+///
+/// # Usage
+/// ```rust
+///     let folders = server.workspace_folders();
+///     folders.add(WorkspaceFolder { uri: backend_uri, name: "backend".to_string() })?;
+///
+///     // Route a document to the folder owning it
+///     let folder = folders.folder_for(&document_uri);
+/// ```
+#[derive(Clone)]
+pub struct WorkspaceFolders {
+    folders: Arc<Mutex<Vec<WorkspaceFolder>>>,
+    capabilities: Arc<RwLock<ServerCapabilities>>,
+    registry: CapabilityRegistry,
+    notifier: Notifier,
+    changes: Arc<watch::Sender<()>>,
+}
+
+impl WorkspaceFolders {
+    pub(crate) fn new(
+        listener: &Listener,
+        capabilities: Arc<RwLock<ServerCapabilities>>,
+        registry: CapabilityRegistry,
+        root_path: &Path,
+    ) -> Self {
+        let workspace_folders = Self {
+            folders: Arc::new(Mutex::new(
+                folder_from_path(utils::working_dir(root_path))
+                    .into_iter()
+                    .collect(),
+            )),
+            capabilities,
+            registry,
+            notifier: listener.notifier(),
+            changes: Arc::new(watch::Sender::new(())),
+        };
+
+        listener
+            .on_fallback_request::<request::WorkspaceFoldersRequest, _, _, _>({
+                let folders = workspace_folders.folders.clone();
+                move |_| {
+                    let folders = folders.lock().clone();
+                    async move { Ok((!folders.is_empty()).then_some(folders)) }
+                }
+            })
+            .detach();
+
+        workspace_folders
+    }
+
+    /// The workspace folders, in the order they were added
+    pub fn folders(&self) -> Vec<WorkspaceFolder> {
+        self.folders.lock().clone()
+    }
+
+    /// The innermost folder containing the uri, `None` if it is outside of the workspace
+    ///
+    /// * `uri`: Uri of a document or a folder
+    pub fn folder_for(&self, uri: &Uri) -> Option<WorkspaceFolder> {
+        self.folders
+            .lock()
+            .iter()
+            .filter(|folder| utils::uri_contains(&folder.uri, uri))
+            .max_by_key(|folder| folder.uri.as_str().len())
+            .cloned()
+    }
+
+    /// Add a folder to the workspace, nothing is sent if it is already part of it
+    ///
+    /// * `folder`: Uri and name of the folder
+    pub fn add(&self, folder: WorkspaceFolder) -> Result<(), Error> {
+        {
+            let mut folders = self.folders.lock();
+            if folders.iter().any(|added| added.uri == folder.uri) {
+                return Ok(());
+            }
+            folders.push(folder.clone());
+        }
+
+        self.changed(vec![folder], Vec::new())
+    }
+
+    /// Remove a folder from the workspace, nothing is sent if it isn't part of it
+    ///
+    /// * `uri`: Uri of the folder
+    pub fn remove(&self, uri: &Uri) -> Result<(), Error> {
+        let removed = {
+            let mut folders = self.folders.lock();
+            match folders.iter().position(|folder| folder.uri == *uri) {
+                Some(index) => folders.remove(index),
+                None => return Ok(()),
+            }
+        };
+
+        self.changed(Vec::new(), vec![removed])
+    }
+
+    // The folders named by the `initialize` request replace ours
+    pub(crate) fn set(&self, folders: Vec<WorkspaceFolder>) {
+        *self.folders.lock() = folders;
+        self.changes.send_replace(());
+    }
+
+    // Notified every time the folders change
+    #[cfg(all(feature = "watcher", target_os = "linux"))]
+    pub(crate) fn subscribe(&self) -> watch::Receiver<()> {
+        self.changes.subscribe()
+    }
+
+    // Whether the server wants `workspace/didChangeWorkspaceFolders`
+    fn notifications_enabled(&self) -> bool {
+        let enabled = self
+            .capabilities
+            .read()
+            .workspace
+            .as_ref()
+            .and_then(|workspace| workspace.workspace_folders.as_ref())
+            .and_then(|folders| folders.change_notifications.as_ref())
+            .is_some_and(|notifications| !matches!(notifications, OneOf::Left(false)));
+
+        enabled
+            || !self
+                .registry
+                .by_method(notification::DidChangeWorkspaceFolders::METHOD)
+                .is_empty()
+    }
+
+    // Tell the server about the change, once the handshake is done. Until then the folders are
+    // only sent with the `initialize` request
+    fn changed(
+        &self,
+        added: Vec<WorkspaceFolder>,
+        removed: Vec<WorkspaceFolder>,
+    ) -> Result<(), Error> {
+        self.changes.send_replace(());
+
+        if !self.notifications_enabled() {
+            return Ok(());
+        }
+
+        match self
+            .notifier
+            .notify_checked::<notification::DidChangeWorkspaceFolders>(
+                DidChangeWorkspaceFoldersParams {
+                    event: WorkspaceFoldersChangeEvent { added, removed },
+                },
+            ) {
+            Err(Error::ServerNotInitialized) => Ok(()),
+            result => result,
+        }
+    }
+}